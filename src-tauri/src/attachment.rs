@@ -0,0 +1,285 @@
+//! 信件附件：小照片或短语音。
+//!
+//! 附件文件保存在应用数据目录的 `attachments` 下，以 uuid 命名。
+//! 信件本身只携带附件的元信息，内容由收方按已收到的字节数向对方请求，
+//! 对方分块发送，所以中途断开后下次触碰可以接着传。收完后校验 sha256。
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::{async_runtime, command, AppHandle, Emitter, Manager};
+use tauri_plugin_blep::mobile::{AttachmentChunk, AttachmentRequest, Message, ReceiptKind};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    ble::{transport::Direction, DeviceBridge},
+    models::Error,
+    receipt,
+    utils::{load_or_default, store_value},
+};
+
+/// 单个附件的大小上限。
+pub const MAX_ATTACHMENT_SIZE: u64 = 512 * 1024;
+/// 一封信最多的附件数。
+pub const MAX_ATTACHMENTS: usize = 4;
+/// 每块的原始字节数。base64 编码后约 384 字节，加上外层 JSON 约 470 字节，
+/// 只有协商到最大 MTU 时才能一次写完；一次通知只能带 MTU - 3 字节，更小的 MTU 下依靠传输层分段。
+const CHUNK_SIZE: usize = 288;
+
+/// 发出过的附件，只响应对这些附件的请求。对方收完后发回送达回执，届时移除。
+const OFFERS: &str = "attachment-offers";
+/// 还没收完的附件。
+const DOWNLOADS: &str = "attachment-downloads";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AttachmentMeta {
+    pub id: Uuid,
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+    /// 内容的 sha256，十六进制
+    pub sha256: String,
+}
+
+/// 发给前端的 `attachment-progress` 事件。
+#[derive(Serialize, Clone)]
+pub struct AttachmentProgress {
+    pub id: Uuid,
+    pub direction: Direction,
+    pub done: u64,
+    pub total: u64,
+}
+
+fn attachment_dir(app: &AppHandle) -> Result<PathBuf, Error> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| Error::Io(e.to_string()))?
+        .join("attachments");
+    fs::create_dir_all(&dir).map_err(|e| Error::Io(e.to_string()))?;
+    Ok(dir)
+}
+
+fn attachment_path(app: &AppHandle, id: Uuid) -> Result<PathBuf, Error> {
+    Ok(attachment_dir(app)?.join(id.to_string()))
+}
+
+fn partial_path(app: &AppHandle, id: Uuid) -> Result<PathBuf, Error> {
+    Ok(attachment_dir(app)?.join(format!("{id}.part")))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// 检查一封信的附件数量和大小。
+pub fn check_limits(attachments: &[AttachmentMeta]) -> Result<(), Error> {
+    if attachments.len() > MAX_ATTACHMENTS {
+        return Err(Error::TooManyAttachments(attachments.len()));
+    }
+    match attachments.iter().find(|a| a.size > MAX_ATTACHMENT_SIZE) {
+        Some(a) => Err(Error::AttachmentTooLarge(a.size)),
+        None => Ok(()),
+    }
+}
+
+/// 发送方：记录随信发出的附件，之后对方会来请求内容。
+pub fn offer(app: &AppHandle, attachments: &[AttachmentMeta]) -> Result<(), Error> {
+    check_limits(attachments)?;
+    let mut offers: HashMap<Uuid, AttachmentMeta> = load_or_default(app, OFFERS)?;
+    for meta in attachments {
+        if !attachment_path(app, meta.id)?.exists() {
+            return Err(Error::AttachmentNotFound(meta.id));
+        }
+        offers.insert(meta.id, meta.clone());
+    }
+    store_value(app, OFFERS, &offers)
+}
+
+/// 收方：登记信件中的附件，等下次触碰时请求。
+pub fn expect(app: &AppHandle, attachments: &[AttachmentMeta]) -> Result<(), Error> {
+    check_limits(attachments)?;
+    let mut downloads: HashMap<Uuid, AttachmentMeta> = load_or_default(app, DOWNLOADS)?;
+    for meta in attachments {
+        if !attachment_path(app, meta.id)?.exists() {
+            downloads.insert(meta.id, meta.clone());
+        }
+    }
+    store_value(app, DOWNLOADS, &downloads)
+}
+
+/// 收方：对每个没收完的附件，从已收到的位置请求。
+pub fn pending_requests(app: &AppHandle) -> Result<Vec<Message>, Error> {
+    let downloads: HashMap<Uuid, AttachmentMeta> = load_or_default(app, DOWNLOADS)?;
+    downloads
+        .keys()
+        .map(|id| {
+            let offset = fs::metadata(partial_path(app, *id)?)
+                .map(|m| m.len())
+                .unwrap_or(0);
+            Ok(Message::AttachmentRequest(AttachmentRequest {
+                attachment: *id,
+                offset,
+            }))
+        })
+        .collect()
+}
+
+/// 发送方：收到请求后从请求的位置开始分块发送。
+pub fn serve(app: AppHandle, request: AttachmentRequest) {
+    async_runtime::spawn(async move {
+        if let Err(e) = send_chunks(&app, &request).await {
+            app.emit("err", e).unwrap();
+        }
+    });
+}
+
+async fn send_chunks(app: &AppHandle, request: &AttachmentRequest) -> Result<(), Error> {
+    let offers: HashMap<Uuid, AttachmentMeta> = load_or_default(app, OFFERS)?;
+    let id = request.attachment;
+    let meta = offers.get(&id).ok_or(Error::AttachmentNotFound(id))?;
+    let data = fs::read(attachment_path(app, id)?).map_err(|e| Error::Io(e.to_string()))?;
+    let start = (request.offset as usize).min(data.len());
+    log::info!("Sending attachment {id} from {start}");
+
+    let state = app.state::<Mutex<DeviceBridge>>();
+    let mut offset = start;
+    for chunk in data[start..].chunks(CHUNK_SIZE) {
+        // 每块单独加锁，块与块之间让出连接，不挡住触碰的收发
+        let msg = Message::AttachmentChunk(AttachmentChunk {
+            attachment: id,
+            offset: offset as u64,
+            data: STANDARD.encode(chunk),
+        });
+        state.lock().await.send_control(msg).await?;
+        offset += chunk.len();
+        app.emit(
+            "attachment-progress",
+            AttachmentProgress {
+                id,
+                direction: Direction::Send,
+                done: offset as u64,
+                total: meta.size,
+            },
+        )
+        .unwrap();
+    }
+    Ok(())
+}
+
+/// 收方：写入收到的一块，收完后校验并发送 `attachment-received` 事件。
+///
+/// 只接受紧接在已收到内容之后的块，重复或跳跃的块被忽略，下次触碰时重新请求。
+pub fn receive_chunk(app: &AppHandle, chunk: &AttachmentChunk) -> Result<(), Error> {
+    let id = chunk.attachment;
+    let mut downloads: HashMap<Uuid, AttachmentMeta> = load_or_default(app, DOWNLOADS)?;
+    let Some(meta) = downloads.get(&id).cloned() else {
+        log::warn!("Chunk of unexpected attachment {id} ignored");
+        return Ok(());
+    };
+    let partial = partial_path(app, id)?;
+    let received = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+    if chunk.offset != received {
+        log::warn!(
+            "Chunk of {id} at {} ignored, expected {received}",
+            chunk.offset
+        );
+        return Ok(());
+    }
+    let data = STANDARD
+        .decode(&chunk.data)
+        .map_err(|e| Error::Load(e.to_string()))?;
+    let done = received + data.len() as u64;
+    if done > meta.size {
+        // 这一块还没写入，文件可能还不存在
+        fs::remove_file(&partial).ok();
+        return Err(Error::AttachmentCorrupted(id));
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial)
+        .and_then(|mut f| f.write_all(&data))
+        .map_err(|e| Error::Io(e.to_string()))?;
+    app.emit(
+        "attachment-progress",
+        AttachmentProgress {
+            id,
+            direction: Direction::Receive,
+            done,
+            total: meta.size,
+        },
+    )
+    .unwrap();
+    if done < meta.size {
+        return Ok(());
+    }
+
+    downloads.remove(&id);
+    store_value(app, DOWNLOADS, &downloads)?;
+    let content = fs::read(&partial).map_err(|e| Error::Io(e.to_string()))?;
+    if sha256_hex(&content) != meta.sha256 {
+        fs::remove_file(&partial).map_err(|e| Error::Io(e.to_string()))?;
+        return Err(Error::AttachmentCorrupted(id));
+    }
+    fs::rename(&partial, attachment_path(app, id)?).map_err(|e| Error::Io(e.to_string()))?;
+    log::info!("Attachment {id} received");
+    receipt::send_back(app, id, ReceiptKind::Delivered)?;
+    app.emit("attachment-received", &meta).unwrap();
+    Ok(())
+}
+
+/// 发送方：对方已经收完附件，不再响应对它的请求。送达回执也可能是信件等其他内容的，这时什么都不做。
+pub fn delivered(app: &AppHandle, id: Uuid) -> Result<(), Error> {
+    let mut offers: HashMap<Uuid, AttachmentMeta> = load_or_default(app, OFFERS)?;
+    if offers.remove(&id).is_none() {
+        return Ok(());
+    }
+    log::info!("Attachment {id} delivered, offer removed");
+    store_value(app, OFFERS, &offers)
+}
+
+/// 把文件作为附件加入应用数据目录，返回附件信息，写信时放入 `Mail::attachments`。
+#[command]
+pub fn add_attachment(
+    app: AppHandle,
+    path: PathBuf,
+    mime: String,
+) -> Result<AttachmentMeta, Error> {
+    let data = fs::read(&path).map_err(|e| Error::Io(e.to_string()))?;
+    let size = data.len() as u64;
+    if size > MAX_ATTACHMENT_SIZE {
+        return Err(Error::AttachmentTooLarge(size));
+    }
+    let meta = AttachmentMeta {
+        id: Uuid::new_v4(),
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        mime,
+        size,
+        sha256: sha256_hex(&data),
+    };
+    fs::write(attachment_path(&app, meta.id)?, &data).map_err(|e| Error::Io(e.to_string()))?;
+    Ok(meta)
+}
+
+/// 附件文件的路径，还没收完时返回错误。
+#[command]
+pub fn attachment_file(app: AppHandle, id: Uuid) -> Result<PathBuf, Error> {
+    let path = attachment_path(&app, id)?;
+    if path.exists() {
+        Ok(path)
+    } else {
+        Err(Error::AttachmentNotFound(id))
+    }
+}
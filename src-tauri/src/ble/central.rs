@@ -1,27 +1,76 @@
-use super::BLEComm;
-use crate::models::Error;
+use super::{transport, BLEComm};
+use crate::models::{Error, MessageType};
 use async_trait::async_trait;
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
+use tauri::{async_runtime, AppHandle};
 use tauri_plugin_blec::{
     self, models::ScanFilter, models::WriteType, Handler, OnDisconnectHandler,
 };
-use tauri_plugin_blep::mobile::Message;
+use tauri_plugin_blep::{
+    mobile::{CharacteristicRole, Message, DEFAULT_MTU},
+    BlepExt,
+};
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
+/// 连接后请求的 MTU，Android 上的最大值。
+const REQUEST_MTU: u16 = 517;
+
+/// 写入时是否等待从端响应。主消息、附件块和订阅确认要确认送达，其余控制消息丢了可以重发，不必等待。
+fn write_type(msg: &Message) -> WriteType {
+    match msg {
+        Message::Subscribed(_) | Message::AttachmentChunk(_) => WriteType::WithResponse,
+        m if m.is_control() => WriteType::WithoutResponse,
+        _ => WriteType::WithResponse,
+    }
+}
+
 /// ble 主端的通信
 pub struct BLECentral {
-    /// 从端 characteristic 的 uuid
+    /// 从端服务的 uuid，即对方设备的 uuid
     uuid: Uuid,
 
+    /// 从端触碰令牌里的会话提示，订阅后出示给从端
+    session_hint: [u8; 8],
+
     /// tauri_plugin_blec 提供的 handler
     handler: &'static Handler,
+
+    /// 协商好的 MTU，知道之前按默认的 23 计算。
+    ///
+    /// blec 没有请求 MTU 的接口，连接后通过 blep 插件另行请求；请求失败时以从端转告的为准。
+    mtu: Arc<AtomicU16>,
+
+    /// 用于发送传输进度事件
+    app: AppHandle,
 }
 
 impl BLECentral {
-    pub fn new(uuid: Uuid) -> Self {
+    pub fn new(uuid: Uuid, session_hint: [u8; 8], app: AppHandle) -> Self {
         Self {
             uuid,
+            session_hint,
             handler: tauri_plugin_blec::get_handler().unwrap(),
+            mtu: Arc::new(AtomicU16::new(DEFAULT_MTU)),
+            app,
+        }
+    }
+
+    /// 向刚连接的从端请求更大的 MTU，失败时沿用现有的值，之后以从端转告的为准。
+    async fn request_mtu(&self, address: String) {
+        let blep = self.app.blep();
+        let result =
+            async_runtime::spawn_blocking(move || blep.request_mtu(&address, REQUEST_MTU)).await;
+        match result {
+            Ok(Ok(mtu)) => {
+                log::info!("MTU negotiated: {mtu}");
+                self.mtu.store(mtu, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => log::warn!("MTU request failed: {e}"),
+            Err(e) => log::warn!("MTU request failed: {e}"),
         }
     }
 }
@@ -31,6 +80,7 @@ impl BLEComm for BLECentral {
     /// 扫描 3 秒，如果找到和包含指定 uuid 的设备就进行连接，返回成功，否则返回失败。
     async fn connect(&mut self) -> Result<mpsc::UnboundedReceiver<Message>, Error> {
         let (noti_sd, noti_rv) = mpsc::unbounded_channel();
+        let noti_rv = transport::reassemble(self.app.clone(), noti_rv);
 
         let (sd, mut rv) = mpsc::channel(100);
         log::info!("Ble central scanning...");
@@ -53,37 +103,63 @@ impl BLEComm for BLECentral {
                     .await
                     .map_err(|e| Error::BleCentralConnect(e.to_string()))?;
                 log::info!("Ble central connected.");
+                self.request_mtu(device.address.clone()).await;
                 break;
             }
         }
 
-        self.handler
-            .subscribe(self.uuid, move |msg: Vec<u8>| {
-                let msg = serde_json::from_slice::<Message>(&msg);
-                noti_sd
-                    .send(msg.expect("received not utf8 string"))
-                    .expect("noti_sd send failed");
-            })
-            .await
-            .map_err(|e| Error::BleCentralSubscribe(e.to_string()))?;
+        // 从端的数据和控制消息分别从两个特征通知过来，汇到同一个接收器
+        for role in [CharacteristicRole::DataTx, CharacteristicRole::Control] {
+            let noti_sd = noti_sd.clone();
+            let mtu = self.mtu.clone();
+            self.handler
+                .subscribe(role.uuid(), move |data: Vec<u8>| {
+                    // MTU 在这里记下，其余交给传输层解析，损坏的数据在那里丢弃
+                    if let Ok(Message::Mtu(m)) = serde_json::from_slice(&data) {
+                        log::info!("Peer reported MTU {m}");
+                        mtu.store(m, Ordering::Relaxed);
+                    } else if noti_sd.send(data).is_err() {
+                        log::warn!("Notification dropped, receiver closed");
+                    }
+                })
+                .await
+                .map_err(|e| Error::BleCentralSubscribe(e.to_string()))?;
+        }
         if !handler.is_connected() {
-            Err(Error::BleCentralDeviceNotFound)
-        } else {
-            Ok(noti_rv)
+            return Err(Error::BleCentralDeviceNotFound);
         }
+        // 告诉从端已经订阅，并出示会话提示，从端核对后才开始发送
+        self.send(Message::Subscribed(self.session_hint)).await?;
+        Ok(noti_rv)
     }
 
     /// 向从端发送消息
     async fn send(&self, message: Message) -> Result<(), Error> {
-        log::info!("Ble central sending message: {message:?}");
-        self.handler
-            .send_data(
-                self.uuid,
-                message.to_string().as_bytes(),
-                WriteType::WithoutResponse,
-            )
-            .await
-            .map_err(|e| Error::BleCenteralSendDataFailed(e.to_string()))
+        log::info!(
+            "Ble central sending message: {}",
+            MessageType::summary(&message)
+        );
+        let handler = self.handler;
+        // 每次写入都放进一个 MTU，分段和中途取消沿用整条消息的写入方式
+        let write_type = write_type(&message);
+        let limit = transport::payload_len(self.mtu().unwrap_or(DEFAULT_MTU));
+        transport::send(
+            &self.app,
+            message,
+            limit,
+            false,
+            move |role, data| async move {
+                handler
+                    .send_data(role.uuid(), &data, write_type)
+                    .await
+                    .map_err(|e| Error::BleCenteralSendDataFailed(e.to_string()))
+            },
+        )
+        .await
+    }
+
+    fn mtu(&self) -> Option<u16> {
+        Some(self.mtu.load(Ordering::Relaxed))
     }
 
     fn is_connected(&self) -> bool {
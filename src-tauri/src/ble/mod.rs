@@ -1,17 +1,28 @@
 pub mod central;
 pub mod peripheral;
-use std::sync::Arc;
+pub mod transport;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::models::{Error, Mail, MessageType};
+use crate::clock::TrustedClock;
+use crate::models::{Error, MessageType};
+use crate::outbox::Outbox;
+use crate::tap::SessionHints;
+use crate::{attachment, disposable, mailbox, plan, receipt, seal, stats, sync};
 use async_trait::async_trait;
 use central::BLECentral;
 use peripheral::BLEPeripheral;
 use std::cmp::Ordering::*;
-use tauri::{async_runtime, AppHandle, Emitter, Wry};
-use tauri_plugin_blep::mobile::{Blep, Message};
-use tokio::sync::{mpsc, Semaphore};
+use tauri::{async_runtime, AppHandle, Emitter, Manager, Wry};
+use tauri_plugin_blep::mobile::{Blep, Message, ReceiptKind};
+use tokio::sync::{mpsc, watch};
 use uuid::Uuid;
 
+/// 从端等待主端订阅的最长时间。
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// BLE 通信的主从端都会实现的 trait
 #[async_trait]
 pub trait BLEComm {
@@ -21,6 +32,16 @@ pub trait BLEComm {
     /// 用于在触碰后等待连接。
     async fn connect(&mut self) -> Result<mpsc::UnboundedReceiver<Message>, Error>;
 
+    /// 协商好的 MTU，不知道时为 `None`。
+    fn mtu(&self) -> Option<u16> {
+        None
+    }
+
+    /// 一次触碰的收发完成后调用，释放不再需要的资源，如从端的广播。
+    fn end_session(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
     fn is_connected(&self) -> bool;
 }
 
@@ -30,55 +51,61 @@ pub struct DeviceBridge {
     message_rx: Option<mpsc::UnboundedReceiver<Message>>,
     next_msg: Option<Message>,
     /// 之后可能会支持与不同的人通信，故需要记录上一次连接的 uuid，如果新读到的不一致，需要断开并重新连接。
-    last_uuid: Option<Uuid>,
-    /// 作为从端通信的时候需要信号量用于确定主端的监听已经设置。
     ///
-    /// 具体地，收到主端的信号后接收增加 permit，表示已经可以发送信息。从端在发送信息的时候等待获得一个 permit。
-    notify_semaphore: Option<Arc<Semaphore>>,
+    /// 接收消息的任务也要读取，所以共享一份，每条消息都按当时的值记录。
+    last_uuid: Arc<Mutex<Option<Uuid>>>,
+    /// 作为从端通信时，主端是否已经订阅通知。
+    ///
+    /// 收到主端的 `Message::Subscribed` 并核对会话提示后置为 `true`，从端发送前等待它成立，否则发出的通知会丢失。
+    /// 在此之前收到的消息来自未经证实的主端，一律丢弃。
+    subscribed: Option<Arc<watch::Sender<bool>>>,
 }
 
 impl DeviceBridge {
-    pub fn new() -> Self {
-        let uuid = Uuid::new_v4();
-        log::info!("uuid generated: {uuid}");
+    pub fn new(uuid: Uuid) -> Self {
         Self {
             communicater: None,
             uuid,
             message_rx: None,
             next_msg: None,
-            notify_semaphore: None,
-            last_uuid: None,
+            subscribed: None,
+            last_uuid: Arc::new(Mutex::new(None)),
         }
     }
 
     /// 连接上另一条设备，根据 uuid 决定自己应该是主端还是从端，然后设置事件监听转发到前端
     /// - 这里规定大的作为主端，小的作为从端。
+    /// - `session_hint` 是对方令牌里的会话提示，作为主端时出示给对方。
     pub async fn connect(
         &mut self,
         uuid: Uuid,
+        session_hint: [u8; 8],
         blep: Arc<Blep<Wry>>,
         handle: AppHandle,
     ) -> Result<(), Error> {
-        if let Some(last) = &self.last_uuid {
-            if uuid.as_bytes() != last.as_bytes() {
-                return Err(Error::Unsupport("暂不支持多次不同触碰".to_string()));
+        {
+            let mut last_uuid = self.last_uuid.lock().unwrap();
+            if let Some(last) = *last_uuid {
+                if uuid.as_bytes() != last.as_bytes() {
+                    return Err(Error::Unsupport("暂不支持多次不同触碰".to_string()));
+                }
+            } else {
+                *last_uuid = Some(uuid);
             }
-        } else {
-            self.last_uuid = Some(uuid);
         }
 
         let mut commu: Box<dyn BLEComm + Send + Sync> =
             match self.uuid.as_u128().cmp(&uuid.as_u128()) {
                 Greater => {
                     log::info!("Act as BLECentral");
-                    let commu = BLECentral::new(uuid);
+                    let commu = BLECentral::new(uuid, session_hint, handle.clone());
                     Box::new(commu)
                 }
                 Less => {
                     log::info!("Act as BLEPeripheral");
-                    let mut commu = BLEPeripheral::new();
+                    let mut commu = BLEPeripheral::new(handle.clone());
                     commu.setup(blep, self.uuid);
-                    self.notify_semaphore = Some(Arc::new(Semaphore::new(0)));
+                    self.subscribed = Some(Arc::new(watch::Sender::new(false)));
                     Box::new(commu)
                 }
                 Equal => {
@@ -103,34 +130,59 @@ impl DeviceBridge {
             self.message_rx.take().unwrap()
         };
 
-        let semaphore = self.notify_semaphore.clone();
+        let subscribed = self.subscribed.clone();
+        let device = self.uuid;
+        let last_uuid = self.last_uuid.clone();
 
         async_runtime::spawn(async move {
             while let Some(msg) = rx.recv().await {
-                log::info!("Received: {:?}", msg);
-
-                if let Some(s) = semaphore.clone() {
-                    s.add_permits(1);
-                }
+                let peer = *last_uuid.lock().unwrap();
+                log::info!("Received: {}", MessageType::summary(&msg));
 
-                match &msg {
-                    Message::Disposable(s) => handle.emit("recv-disposable-msg", s),
-                    Message::Seal(s) => handle.emit("recv-seal-msg", s),
-                    Message::PlanSync(p) => handle.emit("recv-plan-sync", p),
-                    Message::Mail(p) => {
-                        handle.emit("recv-mail", &serde_json::from_str::<Mail>(p).unwrap())
+                if let Some(s) = &subscribed {
+                    if let Message::Subscribed(hint) = &msg {
+                        let now = handle.state::<TrustedClock>().now();
+                        if handle.state::<SessionHints>().redeem(*hint, now) {
+                            log::info!("Peer subscribed");
+                            s.send_replace(true);
+                        } else {
+                            log::warn!("Peer subscribed with unknown session hint");
+                            handle.emit("err", Error::SessionHintMismatch).unwrap();
+                        }
+                    }
+                    if !*s.borrow() {
+                        continue;
                     }
-                    Message::Empty => Ok(()),
                 }
-                .expect("failed to send msg to frontend");
 
-                handle.emit("touching", MessageType::from(&msg)).unwrap();
+                dispatch(&handle, device, peer, &msg);
+
+                if !msg.is_control() {
+                    stats::record(&handle, stats::Direction::Received, &msg, peer)
+                        .unwrap_or_else(|e| handle.emit("err", e).unwrap());
+                    handle.emit("touching", MessageType::from(&msg)).unwrap();
+                }
             }
         });
         log::info!("Message event emmiter set.");
         Ok(())
     }
 
+    /// 一次触碰的收发完成。连接保持不变，之后的回执等消息仍可收发。
+    pub fn end_session(&mut self) -> Result<(), Error> {
+        match self.communicater.as_mut() {
+            Some(c) => c.end_session(),
+            None => Ok(()),
+        }
+    }
+
+    /// 取出等待发送的消息，改由其他途径发出，如写入 NFC 标签。
+    pub fn take_msg(&mut self) -> Option<Message> {
+        self.next_msg
+            .take()
+            .filter(|m| !matches!(m, Message::Empty))
+    }
+
     pub fn clear_msg(&mut self) -> Result<(), Error> {
         self.next_msg = None;
         Ok(())
@@ -140,33 +192,81 @@ impl DeviceBridge {
         if self.next_msg.is_some() && !matches!(self.next_msg.as_ref(), Some(Message::Empty)) {
             return Err(Error::LastMessageNotSend);
         }
-        log::info!("Next message set: {msg:?}");
+        log::info!("Next message set: {}", MessageType::summary(&msg));
         self.next_msg = Some(msg);
         Ok(())
     }
 
-    pub async fn send(&mut self) -> Result<(), Error> {
+    pub async fn send(&mut self, app: &AppHandle) -> Result<(), Error> {
         if self.communicater.is_none() {
             return Err(Error::SendBeforeConnect);
         }
+        self.wait_subscribed().await?;
+
+        let msg = self.next_msg.take().unwrap_or(Message::Empty);
+        self.communicater
+            .as_mut()
+            .unwrap()
+            .send(msg.clone())
+            .await?;
+        // 已经发出，统计失败不影响发送结果
+        stats::record(
+            app,
+            stats::Direction::Sent,
+            &msg,
+            *self.last_uuid.lock().unwrap(),
+        )
+        .unwrap_or_else(|e| app.emit("err", e).unwrap());
+        Ok(())
+    }
 
-        if let Some(s) = self.notify_semaphore.clone() {
-            // 如果是从端，需要等待许可，即收到对方消息，即监听已经设置，再发送消息。
-            let res = s.acquire().await;
+    /// 如果是从端，需要等待主端订阅并核对会话提示后再发送消息。
+    /// 对方可能已经离开，不能一直等下去。
+    async fn wait_subscribed(&self) -> Result<(), Error> {
+        if let Some(s) = &self.subscribed {
+            let mut rx = s.subscribe();
+            let res = tokio::time::timeout(SEND_TIMEOUT, rx.wait_for(|ready| *ready))
+                .await
+                .map_err(|_| Error::SendTimeout)?;
             if let Err(e) = res {
                 return Err(Error::BlePeripheralSendFail(e.to_string()));
             }
         }
+        Ok(())
+    }
 
-        if self.next_msg.is_none() {
-            self.next_msg = Some(Message::Empty);
+    /// 直接发出一条控制消息，不经过 `next_msg`，也不等待主端订阅。
+    ///
+    /// 作为从端时主端还没有出示会话提示就不发，以免发给未经证实的设备。
+    pub async fn send_control(&mut self, msg: Message) -> Result<(), Error> {
+        if self.subscribed.as_ref().is_some_and(|s| !*s.borrow()) {
+            return Err(Error::BlePeripheralSendFail(
+                "peer not subscribed".to_string(),
+            ));
         }
-        self.communicater
-            .as_mut()
-            .unwrap()
-            .send(self.next_msg.take().unwrap())
-            .await?;
-        Ok(())
+        match self.communicater.as_mut() {
+            Some(c) => c.send(msg).await,
+            None => Err(Error::SendBeforeConnect),
+        }
+    }
+
+    /// 在主消息之后发出排队的控制消息，发送失败的放回队列。
+    pub async fn send_controls(&mut self, app: &AppHandle) -> Result<(), Error> {
+        if self.communicater.is_none() {
+            return Err(Error::SendBeforeConnect);
+        }
+        self.wait_subscribed().await?;
+        let outbox = app.state::<Outbox>();
+        let mut result = Ok(());
+        while let Some(msg) = outbox.pop() {
+            if let Err(e) = self.send_control(msg.clone()).await {
+                outbox.restore(msg);
+                result = Err(e);
+                break;
+            }
+        }
+        outbox.save(app)?;
+        result
     }
 
     pub fn is_connected(&self) -> bool {
@@ -176,3 +276,76 @@ impl DeviceBridge {
         }
     }
 }
+
+/// 处理一条收到的消息，结果以事件发给前端。BLE 和 NFC 标签收到的消息都经过这里。
+/// `peer` 是发来消息的设备，不知道时为 `None`。
+pub fn dispatch(app: &AppHandle, device: Uuid, peer: Option<Uuid>, msg: &Message) {
+    match msg {
+        Message::Disposable(s) => match disposable::receive(app, s) {
+            Ok(notice) => app.emit("recv-disposable-msg", notice),
+            Err(e) => app.emit("err", e),
+        },
+        Message::Seal(s) => match seal::receive_proposal(app, s) {
+            Ok(signature) => {
+                seal::send_signature(app.clone(), signature);
+                Ok(())
+            }
+            Err(e) => app.emit("seal-failed", e),
+        },
+        Message::SealSign(s) => match seal::receive_signature(app, s) {
+            Ok(instance) => app.emit("seal-committed", instance),
+            Err(e) => app.emit("seal-failed", e),
+        },
+        Message::PlanSync(p) => match sync::merge_plans(app, p) {
+            Ok(mut merged) => {
+                if !merged.conflicts.is_empty() {
+                    app.emit("plan-sync-conflict", &merged.conflicts).unwrap();
+                }
+                // 只有知道对方是谁时才能判断双方都已确认
+                if let Some(peer) = peer {
+                    match plan::finish_confirmed(app, device, peer, &mut merged.plans) {
+                        Ok(finished) if !finished.is_empty() => {
+                            app.emit("plan-finished", finished).unwrap()
+                        }
+                        Ok(_) => {}
+                        Err(e) => app.emit("err", e).unwrap(),
+                    }
+                }
+                app.emit("recv-plan-sync", merged.plans)
+            }
+            Err(e) => app.emit("err", e),
+        },
+        Message::Mail(p) => match mailbox::receive(app, p) {
+            Ok(mail) => app.emit("recv-mail", mail),
+            Err(e) => app.emit("err", e),
+        },
+        Message::HistorySync(s) => match sync::merge_history(app, s) {
+            Ok(summary) => app.emit("recv-history-sync", summary),
+            Err(e) => app.emit("err", e),
+        },
+        Message::Receipt(r) => {
+            // 附件的送达回执说明对方已经收完，不必再提供
+            if r.kind == ReceiptKind::Delivered {
+                if let Err(e) = attachment::delivered(app, r.item) {
+                    app.emit("err", e).unwrap();
+                }
+            }
+            match receipt::record(app, r) {
+                Ok(_) => app.emit("recv-receipt", r),
+                Err(e) => app.emit("err", e),
+            }
+        }
+        Message::AttachmentRequest(r) => {
+            attachment::serve(app.clone(), r.clone());
+            Ok(())
+        }
+        Message::AttachmentChunk(c) => match attachment::receive_chunk(app, c) {
+            Ok(()) => Ok(()),
+            Err(e) => app.emit("err", e),
+        },
+        // MTU 由主端收到时记下，订阅确认在收到时处理
+        Message::Mtu(_) | Message::Subscribed(_) => Ok(()),
+        Message::Empty => Ok(()),
+    }
+    .expect("failed to send msg to frontend");
+}
@@ -1,13 +1,21 @@
-use super::BLEComm;
-use crate::models::Error;
+use super::{transport, BLEComm};
+use crate::models::{Error, MessageType};
 use async_trait::async_trait;
-use std::sync::Arc;
-use tauri::{async_runtime, Wry};
-use tauri_plugin_blep::mobile::{Blep, ConnectionStatus, Message};
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
+use tauri::{async_runtime, AppHandle, Emitter, Manager, Wry};
+use tauri_plugin_blep::mobile::{
+    AdvertiseOptions, Blep, ConnectionStatus, GattService, Message, DEFAULT_MTU,
+};
 use tokio::sync::mpsc;
 use tokio::sync::watch;
 use uuid::Uuid;
 
+/// 广播的最长时间。对方通常几秒内就会连上，超时后系统自动停止广播，避免一直可被发现。
+const ADVERTISE_TIMEOUT_MS: u32 = 60_000;
+
 /// 封装 BLE 中外设通信。
 pub struct BLEPeripheral {
     /// 用于接收 BLE 收到的消息
@@ -23,15 +31,23 @@ pub struct BLEPeripheral {
 
     /// 是否已经启动广播
     is_advertize_start: bool,
+
+    /// 上次转告主端的 MTU，0 表示还没有转告
+    reported_mtu: AtomicU16,
+
+    /// 用于发送传输进度事件
+    app: AppHandle,
 }
 
 impl BLEPeripheral {
-    pub fn new() -> Self {
+    pub fn new(app: AppHandle) -> Self {
         Self {
             recv_msg_receiver: None,
             connect_watcher: None,
             blep: None,
             is_advertize_start: false,
+            reported_mtu: AtomicU16::new(0),
+            app,
         }
     }
 
@@ -40,13 +56,37 @@ impl BLEPeripheral {
     pub fn setup(&mut self, blep: Arc<Blep<Wry>>, uuid: Uuid) {
         self.blep = Some(blep.clone());
 
-        let (sd, rv) = mpsc::unbounded_channel();
-        self.recv_msg_receiver = Some(rv);
+        // 主端写入数据和控制特征的数据都交给传输层还原，分段的顺序由传输编号和偏移保证
+        let (sd, mut written) = mpsc::unbounded_channel();
+        let (raw_sd, rv) = mpsc::unbounded_channel();
+        async_runtime::spawn(async move {
+            while let Some((_, data)) = written.recv().await {
+                if raw_sd.send(data).is_err() {
+                    break;
+                }
+            }
+        });
+        self.recv_msg_receiver = Some(transport::reassemble(self.app.clone(), rv));
         let (noti_sd, noti_rv) = watch::channel(ConnectionStatus::Disconnected);
         self.connect_watcher = Some(noti_rv);
 
+        let options = AdvertiseOptions {
+            timeout_ms: ADVERTISE_TIMEOUT_MS,
+            ..Default::default()
+        };
+        let service = GattService::standard(
+            serde_json::json!({
+                "device": uuid,
+                "version": self.app.package_info().version.to_string(),
+            })
+            .to_string(),
+        );
+        let app = self.app.clone();
         async_runtime::spawn(async move {
-            blep.setup(sd, noti_sd, uuid).expect("failed to setup blep");
+            match blep.setup(sd, noti_sd, uuid, service, options) {
+                Ok(status) => log::info!("Advertising: {status:?}"),
+                Err(e) => app.emit("err", Error::Advertise(e.to_string())).unwrap(),
+            }
         });
         log::info!("Ble peripheral setup");
         self.is_advertize_start = true;
@@ -56,21 +96,41 @@ impl BLEPeripheral {
 #[async_trait]
 impl BLEComm for BLEPeripheral {
     async fn send(&self, msg: Message) -> Result<(), Error> {
-        let msg = msg.to_string();
-        log::info!("Sending message: {msg:?}");
-        match self.blep.clone() {
-            Some(blep) => match blep.send(msg) {
-                Err(s) => Err(Error::BlePeripheralSendFail(s.to_string())),
-                Ok(ok) => {
-                    if ok.success {
-                        Ok(())
-                    } else {
-                        Err(Error::BlePeripheralSendFail("failed".to_string()))
-                    }
+        log::info!("Sending message: {}", MessageType::summary(&msg));
+        let blep = match self.blep.clone() {
+            Some(blep) => blep,
+            None => return Ok(()),
+        };
+        // 主端请求 MTU 失败时不知道协商的结果，变化后先转告，双方按同样的大小分段
+        let mtu = self.mtu();
+        if let Some(m) = mtu {
+            if self.reported_mtu.swap(m, Ordering::Relaxed) != m {
+                let report = Message::Mtu(m);
+                let sent = blep.send(report.characteristic(true), report.to_string().as_bytes());
+                if !matches!(sent, Ok(ref r) if r.success) {
+                    self.reported_mtu.store(0, Ordering::Relaxed);
+                    log::warn!("Failed to report MTU {m}");
                 }
-            },
-            None => Ok(()),
+            }
         }
+        // 通知不能分成多次，每次都要放进一个 MTU
+        let limit = transport::payload_len(mtu.unwrap_or(DEFAULT_MTU));
+        transport::send(&self.app, msg, limit, true, move |role, data| {
+            let blep = blep.clone();
+            async move {
+                match blep.send(role, &data) {
+                    Err(s) => Err(Error::BlePeripheralSendFail(s.to_string())),
+                    Ok(ok) => {
+                        if ok.success {
+                            Ok(())
+                        } else {
+                            Err(Error::BlePeripheralSendFail("failed".to_string()))
+                        }
+                    }
+                }
+            }
+        })
+        .await
     }
 
     /// 阻塞直到连接成功。
@@ -92,13 +152,34 @@ impl BLEComm for BLEPeripheral {
         }
     }
 
+    /// 会话结束后停止广播，省电也不再被其他设备发现。
+    fn end_session(&mut self) -> Result<(), Error> {
+        if !self.is_advertize_start {
+            return Ok(());
+        }
+        if let Some(blep) = &self.blep {
+            blep.stop_advertising()
+                .map_err(|e| Error::Advertise(e.to_string()))?;
+            log::info!("Advertising stopped");
+        }
+        self.is_advertize_start = false;
+        Ok(())
+    }
+
+    fn mtu(&self) -> Option<u16> {
+        match *self.connect_watcher.as_ref()?.borrow() {
+            ConnectionStatus::Connected { mtu } => Some(mtu),
+            ConnectionStatus::Disconnected => None,
+        }
+    }
+
     fn is_connected(&self) -> bool {
         if self.connect_watcher.is_none() {
             false
         } else {
             matches!(
                 *(self.connect_watcher.as_ref().unwrap().borrow()),
-                ConnectionStatus::Connected
+                ConnectionStatus::Connected { .. }
             )
         }
     }
@@ -0,0 +1,525 @@
+//! 传输层：把较长的消息分段发送，收方收齐后还原。
+//!
+//! 放得下的消息直接以 JSON 发出。放不下的分段发送，每段是二进制的：
+//! `类型(1) | 传输编号(2) | 首段为总长、其余为偏移(4) | 数据`，整数都是大端。
+//! JSON 总以 `{` 或 `"` 开头，不会和分段的类型混淆。头部只有 7 字节，默认 MTU 下每段也能带 13 字节。
+//! 取消只有 `类型(1) | 传输编号(2)`，收方收到后丢弃已收到的部分。
+//!
+//! 分段后主从端的收发都会向前端发送 `transfer-progress` 事件，
+//! 发送中的消息可以通过 `cancel_transfer` 取消。
+
+use std::{
+    collections::HashMap, collections::HashSet, future::Future, sync::Mutex, time::Duration,
+};
+
+use rand::random;
+use serde::Serialize;
+use tauri::{async_runtime, command, AppHandle, Emitter, Manager};
+use tauri_plugin_blep::mobile::{CharacteristicRole, Message};
+use tokio::sync::{mpsc, Notify};
+use uuid::Uuid;
+
+use crate::models::Error;
+
+/// BLE 属性值的长度上限。
+pub const MAX_ATTRIBUTE_LEN: usize = 512;
+
+const FIRST: u8 = 0x01;
+const NEXT: u8 = 0x02;
+const CANCEL: u8 = 0x03;
+/// 分段头部的长度。
+const HEADER_LEN: usize = 7;
+
+#[derive(Serialize, Clone, Copy)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+/// 发给前端的 `transfer-progress` 事件。
+#[derive(Serialize, Clone)]
+pub struct TransferProgress {
+    pub id: Uuid,
+    pub direction: Direction,
+    pub done: u64,
+    pub total: u64,
+}
+
+/// 进行中和被取消的传输。单独托管，取消时不必等待 `DeviceBridge` 的锁。
+#[derive(Default)]
+pub struct Transfers {
+    cancelled: Mutex<HashSet<Uuid>>,
+    active: Mutex<HashSet<Uuid>>,
+    /// 最后一个传输结束时通知
+    idle: Notify,
+}
+
+impl Transfers {
+    fn is_cancelled(&self, id: Uuid) -> bool {
+        self.cancelled.lock().unwrap().contains(&id)
+    }
+
+    fn begin(&self, id: Uuid) {
+        self.active.lock().unwrap().insert(id);
+    }
+
+    /// 只有进行中的传输可以取消，已经结束的不再记下，以免集合一直增长。
+    fn cancel(&self, id: Uuid) {
+        if self.active.lock().unwrap().contains(&id) {
+            self.cancelled.lock().unwrap().insert(id);
+        }
+    }
+
+    fn finish(&self, id: Uuid) {
+        self.cancelled.lock().unwrap().remove(&id);
+        let mut active = self.active.lock().unwrap();
+        if active.remove(&id) && active.is_empty() {
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// 等待所有传输结束，超时返回 `false`。对方中途离开时接收的传输不会结束，所以必须有超时。
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let idle = self.idle.notified();
+        if self.active.lock().unwrap().is_empty() {
+            return true;
+        }
+        tokio::time::timeout(timeout, idle).await.is_ok()
+    }
+}
+
+fn emit_progress(app: &AppHandle, id: Uuid, direction: Direction, done: u64, total: u64) {
+    app.emit(
+        "transfer-progress",
+        TransferProgress {
+            id,
+            direction,
+            done,
+            total,
+        },
+    )
+    .unwrap();
+}
+
+/// 一次通知或写入最多的字节数：ATT 头占 3 字节，也不能超过属性值的上限。
+pub fn payload_len(mtu: u16) -> usize {
+    (mtu as usize).saturating_sub(3).min(MAX_ATTRIBUTE_LEN)
+}
+
+/// 线路上的一段。传输编号只在发送方唯一，事件和取消用的是各自生成的 uuid。
+#[derive(Debug, PartialEq)]
+enum Frame {
+    First {
+        transfer: u16,
+        total: u32,
+        data: Vec<u8>,
+    },
+    Next {
+        transfer: u16,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    Cancel {
+        transfer: u16,
+    },
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, transfer, pos, data) = match self {
+            Frame::First {
+                transfer,
+                total,
+                data,
+            } => (FIRST, transfer, Some(total), data.as_slice()),
+            Frame::Next {
+                transfer,
+                offset,
+                data,
+            } => (NEXT, transfer, Some(offset), data.as_slice()),
+            Frame::Cancel { transfer } => (CANCEL, transfer, None, [].as_slice()),
+        };
+        let mut packet = Vec::with_capacity(HEADER_LEN + data.len());
+        packet.push(kind);
+        packet.extend_from_slice(&transfer.to_be_bytes());
+        if let Some(pos) = pos {
+            packet.extend_from_slice(&pos.to_be_bytes());
+        }
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// 解析一段，不是分段或头部不完整时为 `None`。
+    fn decode(packet: &[u8]) -> Option<Self> {
+        let (&kind, rest) = packet.split_first()?;
+        let transfer = u16::from_be_bytes(rest.get(..2)?.try_into().unwrap());
+        if kind == CANCEL {
+            return Some(Frame::Cancel { transfer });
+        }
+        let pos = u32::from_be_bytes(rest.get(2..6)?.try_into().unwrap());
+        let data = rest[6..].to_vec();
+        match kind {
+            FIRST => Some(Frame::First {
+                transfer,
+                total: pos,
+                data,
+            }),
+            NEXT => Some(Frame::Next {
+                transfer,
+                offset: pos,
+                data,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// 把序列化后的消息切成编码后不超过 `limit` 的分段。
+fn split(data: &[u8], transfer: u16, limit: usize) -> Result<Vec<Frame>, Error> {
+    let size = limit
+        .checked_sub(HEADER_LEN)
+        .filter(|size| *size > 0)
+        .ok_or(Error::WriteLimitTooSmall(limit))?;
+    let total = data.len() as u32;
+    Ok(data
+        .chunks(size)
+        .enumerate()
+        .map(|(i, chunk)| match i {
+            0 => Frame::First {
+                transfer,
+                total,
+                data: chunk.to_vec(),
+            },
+            _ => Frame::Next {
+                transfer,
+                offset: (i * size) as u32,
+                data: chunk.to_vec(),
+            },
+        })
+        .collect())
+}
+
+/// 发送一条消息，`write` 负责把一段写到指定的特征。
+///
+/// `limit` 是一次写入最多的字节数。放得下的消息直接发送；更长的分段从数据特征发送，
+/// 每段编码后都不超过 `limit`，每段之间检查是否被取消。
+pub async fn send<F, Fut>(
+    app: &AppHandle,
+    msg: Message,
+    limit: usize,
+    from_peripheral: bool,
+    mut write: F,
+) -> Result<(), Error>
+where
+    F: FnMut(CharacteristicRole, Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let data = msg.to_string().into_bytes();
+    if data.len() <= limit {
+        return write(msg.characteristic(from_peripheral), data).await;
+    }
+
+    let role = CharacteristicRole::data(from_peripheral);
+    let transfer: u16 = random();
+    let frames = split(&data, transfer, limit)?;
+    let (id, total) = (Uuid::new_v4(), data.len() as u64);
+    let transfers = app.state::<Transfers>();
+    transfers.begin(id);
+    let result = async {
+        let mut done = 0;
+        for frame in frames {
+            if transfers.is_cancelled(id) {
+                log::info!("Transfer {id} cancelled at {done}/{total}");
+                write(role, Frame::Cancel { transfer }.encode()).await?;
+                return Err(Error::TransferCancelled(id));
+            }
+            let len = match &frame {
+                Frame::First { data, .. } | Frame::Next { data, .. } => data.len() as u64,
+                Frame::Cancel { .. } => 0,
+            };
+            write(role, frame.encode()).await?;
+            done += len;
+            emit_progress(app, id, Direction::Send, done, total);
+        }
+        Ok(())
+    }
+    .await;
+    transfers.finish(id);
+    result
+}
+
+/// 还原中的消息。
+struct Partial {
+    /// 本机为这次接收生成的 uuid
+    id: Uuid,
+    total: u32,
+    data: Vec<u8>,
+}
+
+/// 把收到的数据还原为消息：分段收齐后还原，其余按 JSON 解析。
+pub fn reassemble(
+    app: AppHandle,
+    mut raw: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> mpsc::UnboundedReceiver<Message> {
+    let (sd, rv) = mpsc::unbounded_channel();
+    async_runtime::spawn(async move {
+        let mut partial: HashMap<u16, Partial> = HashMap::new();
+        while let Some(packet) = raw.recv().await {
+            let transfers = app.state::<Transfers>();
+            let result = match Frame::decode(&packet) {
+                Some(Frame::Cancel { transfer }) => {
+                    if let Some(p) = partial.remove(&transfer) {
+                        transfers.finish(p.id);
+                        log::info!("Transfer {} cancelled by peer", p.id);
+                        app.emit("transfer-cancelled", p.id).unwrap();
+                    }
+                    continue;
+                }
+                Some(frame) => {
+                    let progress =
+                        |id, done, total| emit_progress(&app, id, Direction::Receive, done, total);
+                    push_frame(&transfers, &mut partial, frame, progress)
+                }
+                None => serde_json::from_slice(&packet)
+                    .map(Some)
+                    .map_err(|e| Error::Load(e.to_string())),
+            };
+            match result {
+                Ok(Some(msg)) => {
+                    if sd.send(msg).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                // 截断或损坏的数据只丢弃
+                Err(e) => log::warn!("Malformed packet ({} bytes): {e:?}", packet.len()),
+            }
+        }
+        // 连接断开时还没收齐的消息不会再收到了，不能让它们一直算作进行中
+        let transfers = app.state::<Transfers>();
+        for p in partial.into_values() {
+            log::info!("Transfer {} dropped with the connection", p.id);
+            transfers.finish(p.id);
+        }
+    });
+    rv
+}
+
+/// 收下一段，收齐时返回还原的消息。出错或丢段时这条消息作废，传输随之结束。
+///
+/// `progress` 收到这次接收的 uuid、已收到和总共的字节数。
+fn push_frame(
+    transfers: &Transfers,
+    partial: &mut HashMap<u16, Partial>,
+    frame: Frame,
+    progress: impl FnOnce(Uuid, u64, u64),
+) -> Result<Option<Message>, Error> {
+    let (transfer, data) = match frame {
+        Frame::First {
+            transfer,
+            total,
+            data,
+        } => {
+            let id = Uuid::new_v4();
+            let fresh = Partial {
+                id,
+                total,
+                data: Vec::with_capacity(total as usize),
+            };
+            // 同一编号上一次的传输没收齐，不会再收齐了
+            if let Some(old) = partial.insert(transfer, fresh) {
+                transfers.finish(old.id);
+            }
+            transfers.begin(id);
+            (transfer, data)
+        }
+        Frame::Next {
+            transfer,
+            offset,
+            data,
+        } => {
+            let Some(p) = partial.get(&transfer) else {
+                log::warn!("Frame of unknown transfer {transfer}");
+                return Ok(None);
+            };
+            if transfers.is_cancelled(p.id) || offset as usize != p.data.len() {
+                // 被取消或丢了一段，这条消息无法还原
+                let p = partial.remove(&transfer).unwrap();
+                transfers.finish(p.id);
+                log::info!("Transfer {} dropped at {offset}", p.id);
+                return Ok(None);
+            }
+            (transfer, data)
+        }
+        Frame::Cancel { .. } => return Ok(None),
+    };
+
+    let p = partial.get_mut(&transfer).unwrap();
+    p.data.extend(data);
+    let (id, done, total) = (p.id, p.data.len() as u64, p.total as u64);
+    progress(id, done, total);
+    if done < total {
+        return Ok(None);
+    }
+
+    let p = partial.remove(&transfer).unwrap();
+    transfers.finish(p.id);
+    serde_json::from_slice(&p.data)
+        .map(Some)
+        .map_err(|e| Error::Load(e.to_string()))
+}
+
+/// 取消正在发送或接收的消息，`id` 来自 `transfer-progress` 事件。
+#[command]
+pub fn cancel_transfer(app: AppHandle, id: Uuid) {
+    log::info!("Cancelling transfer {id}");
+    app.state::<Transfers>().cancel(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_mail() -> Message {
+        Message::Mail("见字如面，".repeat(200))
+    }
+
+    /// 依次收下各段，返回每段的结果。
+    fn receive(
+        transfers: &Transfers,
+        partial: &mut HashMap<u16, Partial>,
+        frames: Vec<Frame>,
+    ) -> Vec<Result<Option<Message>, Error>> {
+        frames
+            .into_iter()
+            .map(|frame| push_frame(transfers, partial, frame, |_, _, _| {}))
+            .collect()
+    }
+
+    #[test]
+    fn payload_fits_mtu_and_attribute_limit() {
+        assert_eq!(payload_len(23), 20);
+        assert_eq!(payload_len(185), 182);
+        assert_eq!(payload_len(517), MAX_ATTRIBUTE_LEN);
+        assert_eq!(payload_len(1024), MAX_ATTRIBUTE_LEN);
+        assert_eq!(payload_len(0), 0);
+    }
+
+    #[test]
+    fn frames_round_trip_through_encoding() {
+        for frame in split(b"hello world", 7, 10).unwrap() {
+            assert_eq!(Frame::decode(&frame.encode()), Some(frame));
+        }
+        let cancel = Frame::Cancel { transfer: 7 };
+        assert_eq!(Frame::decode(&cancel.encode()), Some(cancel));
+    }
+
+    #[test]
+    fn json_is_not_a_frame() {
+        assert_eq!(Frame::decode(long_mail().to_string().as_bytes()), None);
+        assert_eq!(
+            Frame::decode(Message::Subscribed([0; 8]).to_string().as_bytes()),
+            None
+        );
+        assert_eq!(Frame::decode(&[FIRST, 0, 1]), None);
+    }
+
+    #[test]
+    fn frames_fit_default_mtu() {
+        let data = long_mail().to_string().into_bytes();
+        for limit in [payload_len(23), payload_len(185), MAX_ATTRIBUTE_LEN] {
+            for frame in split(&data, 1, limit).unwrap() {
+                assert!(frame.encode().len() <= limit);
+            }
+        }
+    }
+
+    #[test]
+    fn limit_must_leave_room_for_data() {
+        assert!(matches!(
+            split(b"hello", 1, HEADER_LEN),
+            Err(Error::WriteLimitTooSmall(HEADER_LEN))
+        ));
+    }
+
+    #[test]
+    fn frames_reassemble_in_order() {
+        let msg = long_mail();
+        let data = msg.to_string().into_bytes();
+        let frames = split(&data, 3, payload_len(23)).unwrap();
+        assert!(frames.len() > 1);
+
+        let transfers = Transfers::default();
+        let mut partial = HashMap::new();
+        let mut last = (0, 0);
+        let mut result = None;
+        for frame in frames {
+            result = push_frame(&transfers, &mut partial, frame, |_, done, total| {
+                last = (done, total)
+            })
+            .unwrap();
+        }
+        assert_eq!(result.unwrap().to_string(), msg.to_string());
+        assert_eq!(last, (data.len() as u64, data.len() as u64));
+        assert!(partial.is_empty());
+        assert!(transfers.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_frame_drops_message() {
+        let data = long_mail().to_string().into_bytes();
+        let mut frames = split(&data, 3, payload_len(23)).unwrap();
+        frames.remove(1);
+        frames.truncate(2);
+        let transfers = Transfers::default();
+        let mut partial = HashMap::new();
+        let results = receive(&transfers, &mut partial, frames);
+        assert!(matches!(results[1], Ok(None)));
+        assert!(partial.is_empty());
+        assert!(transfers.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupted_message_is_an_error() {
+        let frames = split(b"{not json at all", 3, 10).unwrap();
+        let transfers = Transfers::default();
+        let mut partial = HashMap::new();
+        let results = receive(&transfers, &mut partial, frames);
+        assert!(matches!(results.last(), Some(Err(Error::Load(_)))));
+        assert!(partial.is_empty());
+        assert!(transfers.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancelled_transfer_is_discarded() {
+        let data = long_mail().to_string().into_bytes();
+        let mut frames = split(&data, 3, payload_len(23)).unwrap().into_iter();
+        let transfers = Transfers::default();
+        let mut partial = HashMap::new();
+        let mut id = None;
+        push_frame(
+            &transfers,
+            &mut partial,
+            frames.next().unwrap(),
+            |i, _, _| id = Some(i),
+        )
+        .unwrap();
+        let id = id.unwrap();
+        transfers.cancel(id);
+        assert!(transfers.is_cancelled(id));
+
+        let next = frames.next().unwrap();
+        let result = push_frame(&transfers, &mut partial, next, |_, _, _| {});
+        assert!(matches!(result, Ok(None)));
+        assert!(partial.is_empty());
+        assert!(!transfers.is_cancelled(id));
+    }
+
+    #[test]
+    fn finished_transfer_is_not_recorded_as_cancelled() {
+        let transfers = Transfers::default();
+        let id = Uuid::new_v4();
+        transfers.cancel(id);
+        assert!(!transfers.is_cancelled(id));
+    }
+}
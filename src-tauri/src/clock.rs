@@ -0,0 +1,139 @@
+//! 防篡改的时钟，用于定时信件等不能被提前打开的内容。
+//!
+//! 时钟由一个锚点推算：锚点记录某一刻的可信时间和当时的开机时长，
+//! 之后的时间都由开机时长的增量推得，所以运行期间修改系统时间不会生效。
+//! 锚点会写入 store，下次启动时如果没有重启过手机，还能用开机时长校验系统时间是否被改过。
+//!
+//! 重启过则无法校验，此时时钟未经证实：一般用途仍以系统时间为准，只保证不早于上次记录的时间；
+//! 解锁定时信件则只认上次记录的时间加上重启后的开机时长，关机期间的时间不算。
+//! 直到触碰时收到对方的有效令牌，令牌时间与本机相符，才恢复为可信。
+
+use std::{sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+
+use crate::{
+    models::Error,
+    utils::{load_or_default, store_value},
+};
+
+/// 系统时间和推算时间相差超过这个值时，认为系统时间被修改过。
+const TOLERANCE: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct ClockAnchor {
+    wall: DateTime<Utc>,
+    boot: Duration,
+    /// 重启后未经证实时，`boot` 时刻确定已经到达的时间
+    #[serde(default)]
+    floor: Option<DateTime<Utc>>,
+}
+
+/// 开机以来的时长，包括休眠的时间。
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn boot_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` 是有效的可写 timespec，CLOCK_BOOTTIME 在 Linux 2.6.39 之后都可用。
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// 其他平台没有包含休眠时间的单调时钟，退化为进程启动以来的时长。
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn boot_time() -> Duration {
+    use std::{sync::OnceLock, time::Instant};
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed()
+}
+
+pub struct TrustedClock {
+    anchor: Mutex<ClockAnchor>,
+}
+
+impl Default for TrustedClock {
+    /// 无法读取保存的锚点时直接以系统时间为准。
+    fn default() -> Self {
+        Self {
+            anchor: Mutex::new(ClockAnchor {
+                wall: Utc::now(),
+                boot: boot_time(),
+                floor: None,
+            }),
+        }
+    }
+}
+
+impl TrustedClock {
+    /// 读取上次保存的锚点，校验系统时间后建立新的锚点。
+    pub fn load(app: &AppHandle) -> Result<Self, Error> {
+        let saved: Option<ClockAnchor> = load_or_default(app, "clock-anchor")?;
+        let (system, boot) = (Utc::now(), boot_time());
+        let (wall, floor) = match saved {
+            Some(a) if boot >= a.boot => {
+                let elapsed = boot - a.boot;
+                let expected = a.wall + elapsed;
+                let wall = if (system - expected).abs().to_std().unwrap_or_default() > TOLERANCE {
+                    log::warn!("System clock differs from trusted clock: {system} vs {expected}");
+                    expected
+                } else {
+                    system
+                };
+                (wall, a.floor.map(|f| f + elapsed))
+            }
+            // 手机重启过，无法校验，重启后的开机时长是确定经过的
+            Some(a) => {
+                log::warn!("Rebooted since last anchor, clock unverified");
+                (system.max(a.wall), Some(a.floor.unwrap_or(a.wall) + boot))
+            }
+            None => (system, None),
+        };
+        Ok(Self {
+            anchor: Mutex::new(ClockAnchor { wall, boot, floor }),
+        })
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        let anchor = *self.anchor.lock().unwrap();
+        anchor.wall + boot_time().saturating_sub(anchor.boot)
+    }
+
+    /// 确定已经到达的时间，用于解锁定时信件。可信时与 `now` 相同，未经证实时可能更早。
+    pub fn unlock_now(&self) -> DateTime<Utc> {
+        let anchor = *self.anchor.lock().unwrap();
+        let elapsed = boot_time().saturating_sub(anchor.boot);
+        match anchor.floor {
+            Some(floor) => floor + elapsed,
+            None => anchor.wall + elapsed,
+        }
+    }
+
+    /// 对方的令牌时间与本机相符，本机时钟恢复为可信。
+    ///
+    /// 调用前令牌已经按有效期校验过，双方时间相差不超过令牌的有效期。
+    pub fn confirm(&self, app: &AppHandle) -> Result<(), Error> {
+        let mut anchor = self.anchor.lock().unwrap();
+        if anchor.floor.take().is_none() {
+            return Ok(());
+        }
+        log::info!("Clock confirmed by peer");
+        store_value(app, "clock-anchor", &*anchor)
+    }
+
+    /// 保存当前时刻作为锚点。
+    pub fn save(&self, app: &AppHandle) -> Result<(), Error> {
+        let mut anchor = self.anchor.lock().unwrap();
+        let boot = boot_time();
+        let elapsed = boot.saturating_sub(anchor.boot);
+        *anchor = ClockAnchor {
+            wall: anchor.wall + elapsed,
+            boot,
+            floor: anchor.floor.map(|f| f + elapsed),
+        };
+        store_value(app, "clock-anchor", &*anchor)
+    }
+}
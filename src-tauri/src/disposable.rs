@@ -0,0 +1,140 @@
+//! 一次性消息：仅此一次，一触即焚。
+//!
+//! 收到的一次性消息只保存在内存中，前端只会收到通知，
+//! 需要调用 `reveal_disposable` 取出内容，取出后立即抹除，不能再次取出。
+//! 收到和抹除后分别向对方回执送达和销毁，在下次触碰时发出。
+
+use std::{collections::HashMap, sync::Mutex as StdMutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{async_runtime, command, AppHandle, Emitter, Manager};
+use tauri_plugin_blep::mobile::{Message, ReceiptKind};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    ble::DeviceBridge,
+    models::Error,
+    receipt,
+    utils::{load_or_default, store_value},
+};
+
+/// 通过 BLE 传输的一次性消息。
+#[derive(Serialize, Deserialize)]
+pub struct Disposable {
+    pub id: Uuid,
+    pub body: String,
+    /// 查看时限，超过后前端应关闭显示
+    pub view_secs: Option<u32>,
+}
+
+/// 通知前端收到了一次性消息，不包含内容。
+#[derive(Serialize, Clone)]
+pub struct DisposableNotice {
+    pub id: Uuid,
+    pub view_secs: Option<u32>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct RevealedDisposable {
+    pub body: String,
+    pub view_secs: Option<u32>,
+}
+
+/// 发出的一次性消息，对方何时销毁记录在回执中。
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SentDisposable {
+    pub sent_at: DateTime<Utc>,
+}
+
+struct Pending {
+    body: Zeroizing<String>,
+    view_secs: Option<u32>,
+}
+
+/// 收到但还没有查看的一次性消息。
+#[derive(Default)]
+pub struct DisposableBox {
+    pending: StdMutex<HashMap<Uuid, Pending>>,
+}
+
+/// 收到一次性消息，放入内存，返回给前端的通知。
+pub fn receive(app: &AppHandle, msg: &str) -> Result<DisposableNotice, Error> {
+    let msg: Disposable = serde_json::from_str(msg).map_err(|e| Error::Load(e.to_string()))?;
+    let notice = DisposableNotice {
+        id: msg.id,
+        view_secs: msg.view_secs,
+    };
+    app.state::<DisposableBox>().pending.lock().unwrap().insert(
+        msg.id,
+        Pending {
+            body: Zeroizing::new(msg.body),
+            view_secs: msg.view_secs,
+        },
+    );
+    receipt::send_back(app, msg.id, ReceiptKind::Delivered)?;
+    Ok(notice)
+}
+
+/// 设置一次性消息，`view_secs` 为对方查看的时限。
+#[command]
+pub async fn set_disposable_msg(
+    app: AppHandle,
+    msg: String,
+    view_secs: Option<u32>,
+) -> Result<(), Error> {
+    let id = Uuid::new_v4();
+    let payload = Disposable {
+        id,
+        body: msg,
+        view_secs,
+    };
+    let state = app.state::<Mutex<DeviceBridge>>();
+    let mut guard = state.lock().await;
+    (*guard).set_msg(Message::Disposable(
+        serde_json::to_string(&payload).unwrap(),
+    ))?;
+
+    let mut sent: HashMap<Uuid, SentDisposable> = load_or_default(&app, "disposable-sent")?;
+    sent.insert(
+        id,
+        SentDisposable {
+            sent_at: Utc::now(),
+        },
+    );
+    store_value(&app, "disposable-sent", &sent)
+}
+
+/// 取出一次性消息的内容，只能取出一次。
+///
+/// 取出后内存中的内容被抹除，销毁回执在下次触碰时发给对方；
+/// 有查看时限的消息到时会发出 `disposable-expired` 事件。
+#[command]
+pub fn reveal_disposable(app: AppHandle, id: Uuid) -> Result<RevealedDisposable, Error> {
+    let pending = app
+        .state::<DisposableBox>()
+        .pending
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or(Error::DisposableGone(id))?;
+    let revealed = RevealedDisposable {
+        body: pending.body.to_string(),
+        view_secs: pending.view_secs,
+    };
+    drop(pending);
+
+    if let Some(secs) = revealed.view_secs {
+        let handle = app.clone();
+        async_runtime::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(secs.into())).await;
+            handle.emit("disposable-expired", id).unwrap();
+        });
+    }
+
+    receipt::send_back(&app, id, ReceiptKind::Burned)?;
+    log::info!("Disposable {id} revealed and burned");
+    Ok(revealed)
+}
@@ -0,0 +1,115 @@
+//! 本机身份：固定的设备 uuid 和 ed25519 签名密钥。
+//!
+//! 第一次启动时生成并写入 store，之后一直沿用，对方可以据此确认签名来自同一台设备。
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use tauri::AppHandle;
+use uuid::Uuid;
+
+use crate::{
+    models::Error,
+    utils::{load_or_default, store_value},
+};
+
+const IDENTITY: &str = "identity";
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    device: Uuid,
+    /// base64 编码的签名私钥
+    secret: String,
+}
+
+pub struct Identity {
+    pub device: Uuid,
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// 读取本机身份，没有时生成一个新的。
+    pub fn load(app: &AppHandle) -> Result<Self, Error> {
+        let stored: Option<StoredIdentity> = load_or_default(app, IDENTITY)?;
+        if let Some(stored) = stored {
+            let secret: [u8; 32] = STANDARD
+                .decode(&stored.secret)
+                .ok()
+                .and_then(|b| b.try_into().ok())
+                .ok_or(Error::Load("invalid identity key".to_string()))?;
+            return Ok(Self {
+                device: stored.device,
+                signing: SigningKey::from_bytes(&secret),
+            });
+        }
+
+        let identity = Self::ephemeral();
+        store_value(
+            app,
+            IDENTITY,
+            &StoredIdentity {
+                device: identity.device,
+                secret: STANDARD.encode(identity.signing.to_bytes()),
+            },
+        )?;
+        log::info!("Identity generated: {}", identity.device);
+        Ok(identity)
+    }
+
+    /// 生成一个新的身份，不写入 store。
+    ///
+    /// 读取失败时本次运行先用它，下次启动会重新读取已保存的身份。
+    pub fn ephemeral() -> Self {
+        Self {
+            device: Uuid::new_v4(),
+            signing: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// base64 编码的公钥。
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.verifying_key())
+    }
+
+    pub fn verifying_key(&self) -> [u8; 32] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    /// 签名，返回 base64 编码的签名。
+    pub fn sign(&self, data: &[u8]) -> String {
+        STANDARD.encode(self.sign_bytes(data))
+    }
+
+    pub fn sign_bytes(&self, data: &[u8]) -> [u8; 64] {
+        self.signing.sign(data).to_bytes()
+    }
+
+    /// 与对方的 X25519 共享密钥，由双方的 ed25519 密钥换算得到，双方算出的结果相同。
+    pub fn shared_secret(&self, peer_public_key: &[u8; 32]) -> Result<[u8; 32], Error> {
+        let peer = VerifyingKey::from_bytes(peer_public_key).map_err(|_| Error::BadSignature)?;
+        // ed25519 私钥哈希的前半部分即是 X25519 的标量
+        let scalar: [u8; 32] = Sha512::digest(self.signing.to_bytes())[..32]
+            .try_into()
+            .unwrap();
+        Ok(peer.to_montgomery().mul_clamped(scalar).to_bytes())
+    }
+}
+
+/// 用 base64 编码的公钥验证 base64 编码的签名。
+pub fn verify(public_key: &str, data: &[u8], signature: &str) -> Result<(), Error> {
+    let key: [u8; 32] = STANDARD
+        .decode(public_key)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(Error::BadSignature)?;
+    let signature: [u8; 64] = STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(Error::BadSignature)?;
+    VerifyingKey::from_bytes(&key)
+        .and_then(|key| key.verify(data, &Signature::from_bytes(&signature)))
+        .map_err(|_| Error::BadSignature)
+}
@@ -1,58 +1,161 @@
+use std::time::{Duration, Instant};
+
 use tauri_plugin_fs::FsExt;
 use tokio::sync::Mutex;
 mod models;
 use tauri::{async_runtime, AppHandle, Emitter, Manager};
 use tauri_plugin_blep::{self, BlepExt};
+mod attachment;
 mod ble;
-use ble::DeviceBridge;
-use models::Error;
+use ble::{transport::Transfers, DeviceBridge};
+use clock::TrustedClock;
+use disposable::DisposableBox;
+use identity::Identity;
+use models::{DeviceId, Error};
+use outbox::Outbox;
+use reader::NfcReader;
+use reminder::Reminders;
+use seal::SealSession;
+use search::SearchIndex;
+use tap::{SeenNonces, SessionHints};
 use tauri_plugin_log::{Target, TargetKind};
-use tauri_plugin_nfc2::{self, Nfc2Ext};
-use tokio::sync::{mpsc::unbounded_channel, watch};
+use tokio::sync::mpsc::unbounded_channel;
 use utils::*;
 use uuid::Uuid;
+mod clock;
+mod disposable;
+mod identity;
+mod mailbox;
+mod outbox;
+mod pairing;
+mod plan;
+mod reader;
+mod receipt;
+mod reminder;
+mod seal;
+mod search;
+mod stats;
+mod sync;
+mod tag;
+mod tap;
+mod timeline;
+mod timestamp;
 mod utils;
 
-/// 初始化读卡器，读到卡时进行连接和发送等待发送的事件。
+/// 同一台设备在上次会话结束后这么久内再次触碰，并入上次会话。
+const TAP_DEBOUNCE: Duration = Duration::from_secs(3);
+/// 新的触碰等待进行中的传输结束的最长时间。
+const TRANSFER_WAIT: Duration = Duration::from_secs(30);
+
+/// 初始化读卡器，读到卡时验证对方的令牌，通过后进行连接和发送等待发送的事件。
 ///
 /// # Note
-/// - 被读的设备读到读卡的设备的 uuid 也是通过 reader 提供的 channel 来返回的。
+/// - 被读的设备读到读卡的设备的令牌也是通过 reader 提供的 channel 来返回的。
 /// - 注册向前端发送接收到的事件是在 connect 里，所以在这里也注册了。
-fn start_reader(app: AppHandle, uuid: Uuid) -> Result<(), Error> {
-    let placeholder = Uuid::new_v4();
-    log::info!("{}", placeholder.simple());
-    let (sd, mut rv) = watch::channel(placeholder);
+fn start_reader(app: AppHandle) -> Result<(), Error> {
+    let (sd, mut rv) = unbounded_channel();
     let (err_sd, mut err_rv) = unbounded_channel();
+    let (tag_sd, mut tag_rv) = unbounded_channel();
+    app.manage(NfcReader::new(sd, err_sd, tag_sd));
     let app_handle = app.clone();
     async_runtime::spawn(async move {
-        let nfc = app_handle.nfc2();
-        nfc.init_nfc_reader(sd, err_sd, uuid).unwrap_or_else(|e| {
-            app_handle
-                .emit("err", Error::InitNfc(e.to_string()))
-                .unwrap();
-        });
+        app_handle
+            .state::<NfcReader>()
+            .init(&app_handle)
+            .unwrap_or_else(|e| {
+                app_handle.emit("err", e).unwrap();
+            });
+        tap::refresh(app_handle.clone());
     });
 
     let app_handle = app.clone();
     async_runtime::spawn(async move {
-        while rv.changed().await.is_ok() {
-            let uuid = *rv.borrow();
+        let mut last_session: Option<(Uuid, Instant)> = None;
+        while let Some(token) = rv.recv().await {
+            tap::rotate(&app_handle);
+            let token = match tap::verify(&app_handle, &token) {
+                Ok(token) => token,
+                Err(Error::TapTokenReplayed) => {
+                    // 手机贴在一起时同一个令牌会被读到多次
+                    log::info!("Duplicate tap ignored");
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Rejected tap token: {e:?}");
+                    app_handle.emit("err", e).unwrap();
+                    continue;
+                }
+            };
+            let uuid = token.device;
+            if let Some((last, ended)) = last_session {
+                if last == uuid && ended.elapsed() < TAP_DEBOUNCE {
+                    log::info!("Tap of {uuid} merged into last session");
+                    continue;
+                }
+            }
 
             log::info!("Read uuid: {}", uuid.to_string());
 
+            // 会话期间暂停读卡，避免一次触碰被读到多次
+            let reader = app_handle.state::<NfcReader>();
+            reader
+                .set_in_session(&app_handle, true)
+                .unwrap_or_else(|e| app_handle.emit("err", e).unwrap());
+            // 上次的传输还没结束时排队等待，不和它抢连接
+            if !app_handle
+                .state::<Transfers>()
+                .wait_idle(TRANSFER_WAIT)
+                .await
+            {
+                log::warn!("Transfer still in progress, start session anyway");
+            }
+
             let state = app_handle.state::<Mutex<DeviceBridge>>();
             let mut guard = state.lock().await;
             if !(*guard).is_connected() {
                 log::info!("Status: Disconnected; Try to connect...");
 
                 (*guard)
-                    .connect(uuid, app_handle.blep(), app_handle.clone())
+                    .connect(
+                        uuid,
+                        token.session_hint,
+                        app_handle.blep(),
+                        app_handle.clone(),
+                    )
                     .await
                     .unwrap_or_else(|e| {
                         app_handle.emit("err", e).unwrap();
                     });
             }
-            (*guard).send().await.unwrap_or_else(|e| {
+            (*guard).send(&app_handle).await.unwrap_or_else(|e| {
+                app_handle.emit("err", e).unwrap();
+            });
+            (*guard)
+                .send_controls(&app_handle)
+                .await
+                .unwrap_or_else(|e| {
+                    app_handle.emit("err", e).unwrap();
+                });
+            for request in attachment::pending_requests(&app_handle).unwrap_or_default() {
+                if let Err(e) = (*guard).send_control(request).await {
+                    app_handle.emit("err", e).unwrap();
+                    break;
+                }
+            }
+            (*guard)
+                .end_session()
+                .unwrap_or_else(|e| app_handle.emit("err", e).unwrap());
+            reader
+                .set_in_session(&app_handle, false)
+                .unwrap_or_else(|e| app_handle.emit("err", e).unwrap());
+            last_session = Some((uuid, Instant::now()));
+        }
+    });
+
+    let app_handle = app.clone();
+    async_runtime::spawn(async move {
+        while let Some(record) = tag_rv.recv().await {
+            tag::receive(&app_handle, &record).unwrap_or_else(|e| {
                 app_handle.emit("err", e).unwrap();
             });
         }
@@ -61,9 +164,10 @@ fn start_reader(app: AppHandle, uuid: Uuid) -> Result<(), Error> {
     let app_handle = app.clone();
     async_runtime::spawn(async move {
         while let Some(e) = err_rv.recv().await {
+            log::warn!("Nfc error: {e:?}");
             app_handle
-                .emit("err", format!("{e:?}"))
-                .expect("failed to emit nfc readed uuid");
+                .emit("nfc-error", e)
+                .expect("failed to emit nfc error");
         }
     });
     Ok(())
@@ -73,6 +177,7 @@ fn start_reader(app: AppHandle, uuid: Uuid) -> Result<(), Error> {
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_biometric::init())
+        .plugin(tauri_plugin_barcode_scanner::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_store::Builder::new().build())
         .plugin(
@@ -91,16 +196,28 @@ pub fn run() {
         .plugin(tauri_plugin_blec::init())
         .plugin(tauri_plugin_blep::init())
         .plugin(tauri_plugin_nfc2::init())
+        .plugin(tauri_plugin_notification::init())
         .invoke_handler(tauri::generate_handler![
             request_blep_bluetooth_permissions,
+            open_nfc_settings,
+            reader::pause_reader,
+            reader::resume_reader,
+            reader::reader_status,
+            reader::reinit_reader,
+            tag::write_tag,
+            tag::cancel_write_tag,
+            pairing::pairing_code,
+            pairing::pair_with_code,
             store_sealed_instances,
             load_sealed_instances,
             store_finished_plan_list,
             load_finished_plan_list,
             store_disposable_drafts,
             load_disposable_drafts,
-            set_disposable_msg,
-            set_seal_msg,
+            disposable::set_disposable_msg,
+            disposable::reveal_disposable,
+            seal::set_seal_msg,
+            seal::verify_seal,
             set_plan_sync_msg,
             load_plan_drafts,
             store_plan_drafts,
@@ -112,17 +229,65 @@ pub fn run() {
             store_mail_drafts_covers,
             load_mail_drafts_covers,
             set_mail_msg,
-            delete_mail
+            delete_mail,
+            set_history_sync_msg,
+            load_sent_mail_covers,
+            plan::confirm_plan_done,
+            plan::toggle_checklist_item,
+            receipt::load_receipts,
+            attachment::add_attachment,
+            attachment::attachment_file,
+            ble::transport::cancel_transfer,
+            search::search,
+            timeline::timeline,
+            reminder::load_reminder_rules,
+            reminder::store_reminder_rules,
+            reminder::upcoming_reminders,
+            stats::insights
         ])
         .setup(|app| {
             let scope = app.fs_scope();
             let data_dir = app.path().data_dir().unwrap();
             scope.allow_directory(data_dir, true).unwrap();
+            timestamp::migrate(app.handle()).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+            });
+            // 索引建立失败时先用空索引，错误交给前端显示
+            app.manage(SearchIndex::build(app.handle()).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+                SearchIndex::default()
+            }));
+            // 锚点读取失败时直接以系统时间为准，应用仍能启动，错误交给前端显示
+            app.manage(TrustedClock::load(app.handle()).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+                TrustedClock::default()
+            }));
+            mailbox::watch_unlocks(app.handle().clone());
+            app.manage(Reminders::default());
+            reminder::watch(app.handle().clone());
+
+            app.manage(DisposableBox::default());
+            app.manage(Transfers::default());
+            // 队列读取失败时从空队列开始，错误交给前端显示
+            app.manage(Outbox::load(app.handle()).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+                Outbox::default()
+            }));
 
-            let bridge = DeviceBridge::new();
-            start_reader(app.handle().clone(), bridge.uuid).unwrap_or_else(|e| {
+            // 身份读取失败时本次运行先用临时身份，应用仍能启动，错误交给前端显示
+            let identity = Identity::load(app.handle()).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+                Identity::ephemeral()
+            });
+            let bridge = DeviceBridge::new(identity.device);
+            app.manage(identity);
+            app.manage(SealSession::default());
+            app.manage(SeenNonces::default());
+            app.manage(SessionHints::default());
+            start_reader(app.handle().clone()).unwrap_or_else(|e| {
                 app.emit("err", e).unwrap();
             });
+            app.manage(DeviceId(bridge.uuid));
             app.manage(Mutex::new(bridge));
             Ok(())
         })
@@ -0,0 +1,107 @@
+//! 信箱：收到的信件由后端直接存入收件箱，定时信件在解锁前不能读取正文。
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::Utc;
+use serde::Serialize;
+use tauri::{async_runtime, AppHandle, Emitter, Manager};
+use tauri_plugin_blep::mobile::ReceiptKind;
+use uuid::Uuid;
+
+use crate::{
+    attachment,
+    clock::TrustedClock,
+    models::{Error, Mail, MailCover, MailCoverList},
+    receipt, sync,
+    utils::{load_or_default, store_value},
+};
+
+/// 检查定时信件的最长间隔，同时也是保存时钟锚点的间隔。
+const UNLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 发给前端的新信件通知。
+#[derive(Serialize, Clone)]
+pub struct ReceivedMail {
+    pub id: Uuid,
+    pub cover: MailCover,
+}
+
+/// 把收到的信件存入收件箱，收到的时间作为信件的时间。
+///
+/// 信件沿用发送方的 uuid，回执和历史同步都以它对应到同一封信。
+pub fn receive(app: &AppHandle, mail: &str) -> Result<ReceivedMail, Error> {
+    let mail: Mail = serde_json::from_str(mail).map_err(|e| Error::Load(e.to_string()))?;
+    let id = mail.id.unwrap_or_else(Uuid::new_v4);
+    attachment::expect(app, &mail.attachments)?;
+    let cover = MailCover {
+        sealed: true,
+        cover: mail.cover,
+        timestamp: Utc::now(),
+        unlock_at: mail.unlock_at,
+        peer: mail.from,
+    };
+    store_value(app, &format!("mail-{}", id), &mail.inner)?;
+    let mut covers: MailCoverList = load_or_default(app, sync::INBOX_MAIL_LIST)?;
+    covers.mails.insert(id, cover.clone());
+    store_value(app, sync::INBOX_MAIL_LIST, &covers)?;
+    log::info!("Mail {id} received");
+    receipt::send_back(app, id, ReceiptKind::Delivered)?;
+    Ok(ReceivedMail { id, cover })
+}
+
+/// 收件箱中的定时信件未到解锁时间时返回错误。
+pub fn ensure_unlocked(app: &AppHandle, uuid: Uuid) -> Result<(), Error> {
+    let covers: MailCoverList = load_or_default(app, sync::INBOX_MAIL_LIST)?;
+    match covers.mails.get(&uuid).and_then(|c| c.unlock_at) {
+        Some(unlock_at) if app.state::<TrustedClock>().unlock_now() < unlock_at => {
+            Err(Error::MailLocked(unlock_at))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 收件箱中有信件从未拆封变为已拆封时，向发送方回执已拆开。
+pub fn send_opened_receipts(
+    app: &AppHandle,
+    old: &MailCoverList,
+    new: &MailCoverList,
+) -> Result<(), Error> {
+    for (id, cover) in &new.mails {
+        if !cover.sealed && old.mails.get(id).is_some_and(|c| c.sealed) {
+            receipt::send_back(app, *id, ReceiptKind::Opened)?;
+        }
+    }
+    Ok(())
+}
+
+/// 定时检查收件箱，定时信件到达解锁时间时向前端发送 `mail-unlocked` 事件。
+pub fn watch_unlocks(app: AppHandle) {
+    async_runtime::spawn(async move {
+        let mut locked: HashSet<Uuid> = HashSet::new();
+        loop {
+            let clock = app.state::<TrustedClock>();
+            let now = clock.unlock_now();
+            let covers: MailCoverList =
+                load_or_default(&app, sync::INBOX_MAIL_LIST).unwrap_or_default();
+
+            let mut next_check = UNLOCK_CHECK_INTERVAL;
+            for (id, cover) in &covers.mails {
+                let Some(unlock_at) = cover.unlock_at else {
+                    continue;
+                };
+                if unlock_at > now {
+                    locked.insert(*id);
+                    let wait = (unlock_at - now).to_std().unwrap_or_default();
+                    next_check = next_check.min(wait);
+                } else if locked.remove(id) {
+                    log::info!("Mail {id} unlocked");
+                    app.emit("mail-unlocked", id).unwrap();
+                }
+            }
+            clock.save(&app).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+            });
+            tokio::time::sleep(next_check).await;
+        }
+    });
+}
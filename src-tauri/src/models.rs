@@ -1,9 +1,16 @@
 use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tauri_plugin_blep::mobile::{Message, Plan};
 use uuid::Uuid;
 
+use crate::{
+    attachment::AttachmentMeta,
+    seal::{SealContext, SealSignature},
+    timestamp,
+};
+
 #[derive(Debug, Serialize, Clone)]
 pub enum Error {
     BleCentralDiscover(String),
@@ -16,18 +23,59 @@ pub enum Error {
     ReceiveBeforeConnect,
     ConnectBeforeSetup,
     BlePeripheralSendFail(String),
+    WriteLimitTooSmall(usize),
+    Advertise(String),
     RequestBlueTooth(String),
     InitNfc(String),
     Lucky(String),
     Store(String),
     Load(String),
     Unsupport(String),
+    PlanNotFound(Uuid),
+    ChecklistItemNotFound(Uuid),
+    MailLocked(DateTime<Utc>),
+    DisposableGone(Uuid),
+    Io(String),
+    AttachmentNotFound(Uuid),
+    AttachmentTooLarge(u64),
+    TooManyAttachments(usize),
+    AttachmentCorrupted(Uuid),
+    TransferCancelled(Uuid),
+    NoSealPending,
+    SealMismatch,
+    SealNotFound(Uuid),
+    BadSignature,
+    InvalidSealContext(String),
+    Notify(String),
+    InvalidReminderRule(String),
+    InvalidTapToken(String),
+    TapTokenExpired,
+    TapTokenReplayed,
+    PeerKeyMismatch(Uuid),
+    NfcSettings(String),
+    SendTimeout,
+    UnknownPeer(Uuid),
+    NoTagPeer,
+    NothingToWrite,
+    TagWrite(String),
+    InvalidTag(String),
+    TagNotForUs,
+    TagTooLarge(usize),
+    DisposableOnTag,
+    SealOnTag,
+    InvalidPairingCode,
+    ReaderStopped,
+    SessionHintMismatch,
 }
 
+/// 本机的 uuid。单独托管一份，读取时不必等待 `DeviceBridge` 的锁。
+#[derive(Clone, Copy)]
+pub struct DeviceId(pub Uuid);
+
 #[derive(Deserialize, Serialize)]
 pub struct MessageDraft {
-    title: String,
-    body: String,
+    pub title: String,
+    pub body: String,
 }
 
 #[derive(Deserialize, Serialize, Default)]
@@ -35,10 +83,23 @@ pub struct DisposableDrafts {
     pub drafts: Vec<MessageDraft>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct Instance {
+    /// 双方共同的 uuid，旧版本记录没有
+    #[serde(default)]
+    pub id: Option<Uuid>,
     pub instance: String,
-    pub time: String,
+    #[serde(deserialize_with = "timestamp::lenient")]
+    pub time: DateTime<Utc>,
+    /// 打卡时的位置、天气、照片
+    #[serde(default)]
+    pub context: SealContext,
+    /// 双方的设备 uuid，从小到大
+    #[serde(default)]
+    pub devices: Vec<Uuid>,
+    /// 双方的签名，与 `devices` 顺序相同
+    #[serde(default)]
+    pub signatures: Vec<SealSignature>,
 }
 
 #[derive(Deserialize, Serialize, Default)]
@@ -46,10 +107,14 @@ pub struct SealedInstances {
     pub instances: Vec<Instance>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct FinishedPlan {
+    /// 计划的 uuid，旧版本记录没有
+    #[serde(default)]
+    pub id: Option<Uuid>,
     pub plan: Plan,
-    pub time: String,
+    #[serde(deserialize_with = "timestamp::lenient")]
+    pub time: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Default)]
@@ -68,12 +133,19 @@ impl From<tauri_plugin_store::Error> for Error {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub enum MessageType {
     Disposable,
     Seal,
+    SealSign,
     PlanSync,
     Mail,
+    HistorySync,
+    Receipt,
+    AttachmentRequest,
+    AttachmentChunk,
+    Mtu,
+    Subscribed,
     Empty,
 }
 
@@ -84,31 +156,83 @@ impl MessageType {
             Message::Empty => Self::Empty,
             Message::PlanSync(_) => Self::PlanSync,
             Message::Seal(_) => Self::Seal,
+            Message::SealSign(_) => Self::SealSign,
             Message::Mail(_) => Self::Mail,
+            Message::HistorySync(_) => Self::HistorySync,
+            Message::Receipt(_) => Self::Receipt,
+            Message::AttachmentRequest(_) => Self::AttachmentRequest,
+            Message::AttachmentChunk(_) => Self::AttachmentChunk,
+            Message::Mtu(_) => Self::Mtu,
+            Message::Subscribed(_) => Self::Subscribed,
+        }
+    }
+
+    /// 日志中代表一条消息：只写类型和对应内容的 uuid，不写内容本身。
+    pub fn summary(val: &Message) -> String {
+        #[derive(Deserialize)]
+        struct WithId {
+            id: Option<Uuid>,
+        }
+
+        let id = match val {
+            Message::Receipt(r) => Some(r.item),
+            Message::AttachmentRequest(r) => Some(r.attachment),
+            Message::AttachmentChunk(c) => Some(c.attachment),
+            Message::Disposable(s) | Message::Seal(s) | Message::Mail(s) => {
+                serde_json::from_str::<WithId>(s).ok().and_then(|w| w.id)
+            }
+            _ => None,
+        };
+        match id {
+            Some(id) => format!("{:?} {id}", Self::from(val)),
+            None => format!("{:?}", Self::from(val)),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Mail {
-    cover: String,
-    inner: MailInner,
+    /// 发送方的 uuid，收方沿用作收件箱中的 uuid
+    #[serde(default)]
+    pub id: Option<Uuid>,
+    /// 发送方的设备 uuid
+    #[serde(default)]
+    pub from: Option<Uuid>,
+    pub cover: String,
+    pub inner: MailInner,
+    /// 寄出的时间，由发送方在设置消息时记录
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>,
+    /// 在这个时间之前收信方无法打开信件
+    #[serde(default)]
+    pub unlock_at: Option<DateTime<Utc>>,
+    /// 附件信息，内容在之后的触碰中分块传输
+    #[serde(default)]
+    pub attachments: Vec<AttachmentMeta>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct MailInner {
-    title: String,
-    body: String,
+    pub title: String,
+    pub body: String,
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct MailCover {
-    sealed: bool,
-    cover: String,
-    timestamp: String,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MailCover {
+    /// 收件箱中表示未拆封；草稿中表示已漆封。
+    pub sealed: bool,
+    pub cover: String,
+    #[serde(deserialize_with = "timestamp::lenient")]
+    pub timestamp: DateTime<Utc>,
+    /// 定时信件的解锁时间
+    #[serde(default)]
+    pub unlock_at: Option<DateTime<Utc>>,
+    /// 信件另一方的设备 uuid，草稿和旧版本的信件没有
+    #[serde(default)]
+    pub peer: Option<Uuid>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct MailCoverList {
-    mails: HashMap<Uuid, MailCover>,
+    pub mails: HashMap<Uuid, MailCover>,
 }
@@ -0,0 +1,55 @@
+//! 待发送的控制消息。
+//!
+//! 控制消息不需要用户触发，例如各种回执。它们排队等待下一次触碰，
+//! 跟在主消息之后发出，发送失败的留在队列中等下次再发。
+//! 队列保存在 store 中，应用重启后不会丢失。
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use tauri::AppHandle;
+use tauri_plugin_blep::mobile::Message;
+
+use crate::{
+    models::{Error, MessageType},
+    utils::{load_or_default, store_value},
+};
+
+const OUTBOX: &str = "outbox";
+
+#[derive(Default)]
+pub struct Outbox {
+    queue: Mutex<VecDeque<Message>>,
+}
+
+impl Outbox {
+    pub fn load(app: &AppHandle) -> Result<Self, Error> {
+        let queue: VecDeque<Message> = load_or_default(app, OUTBOX)?;
+        if !queue.is_empty() {
+            log::info!("{} control messages restored", queue.len());
+        }
+        Ok(Self {
+            queue: Mutex::new(queue),
+        })
+    }
+
+    pub fn push(&self, app: &AppHandle, msg: Message) -> Result<(), Error> {
+        log::info!("Control message queued: {}", MessageType::summary(&msg));
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(msg);
+        store_value(app, OUTBOX, &*queue)
+    }
+
+    /// 取出队首的消息，发送完成后需要调用 `save` 保存队列。
+    pub fn pop(&self) -> Option<Message> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    /// 发送失败的消息放回队首。
+    pub fn restore(&self, msg: Message) {
+        self.queue.lock().unwrap().push_front(msg);
+    }
+
+    pub fn save(&self, app: &AppHandle) -> Result<(), Error> {
+        store_value(app, OUTBOX, &*self.queue.lock().unwrap())
+    }
+}
@@ -0,0 +1,29 @@
+//! 扫码配对：没有 NFC 或 NFC 关闭时，双方互相扫对方的二维码代替触碰。
+//!
+//! 二维码里是和 NFC 交换的相同的签名令牌，扫到后交给同一条流程验证和连接。
+//! BLE 的主从端由双方 uuid 决定，双方都要连接才能建立，所以需要互相扫一次。
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tauri::{command, AppHandle, Manager};
+
+use crate::{models::Error, reader::NfcReader, tap};
+
+const PREFIX: &str = "WHISPACT:";
+
+/// 本机的配对码，用于生成二维码。令牌很快过期，前端需要定时刷新。
+#[command]
+pub fn pairing_code(app: AppHandle) -> String {
+    format!("{PREFIX}{}", URL_SAFE_NO_PAD.encode(tap::issue(&app)))
+}
+
+/// 扫到对方的配对码。
+#[command]
+pub fn pair_with_code(app: AppHandle, code: String) -> Result<(), Error> {
+    let token = code
+        .trim()
+        .strip_prefix(PREFIX)
+        .and_then(|c| URL_SAFE_NO_PAD.decode(c).ok())
+        .ok_or(Error::InvalidPairingCode)?;
+    log::info!("Pairing code scanned");
+    app.state::<NfcReader>().submit(token)
+}
@@ -0,0 +1,125 @@
+//! 共同计划：子任务、重复周期和双方确认完成。
+
+use chrono::{DateTime, Days, Months, NaiveDate, Utc};
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_blep::mobile::{Message, Plan, Plans, Recurrence};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    ble::DeviceBridge,
+    models::{DeviceId, Error, FinishedPlan, FinishedPlanList, PlanDrafts},
+    sync,
+    utils::{load_or_default, store_value},
+};
+
+/// 重复计划完成后的下一次，目标日期按周期顺延，子任务和确认清空。
+fn next_occurrence(plan: &Plan, today: NaiveDate) -> Option<Plan> {
+    let base = plan.due.unwrap_or(today);
+    let due = match plan.recurrence? {
+        Recurrence::Daily => base.checked_add_days(Days::new(1)),
+        Recurrence::Weekly => base.checked_add_days(Days::new(7)),
+        Recurrence::Monthly => base.checked_add_months(Months::new(1)),
+        Recurrence::Yearly => base.checked_add_months(Months::new(12)),
+    }?;
+    let mut next = plan.clone();
+    next.due = Some(due);
+    next.confirmed_by.clear();
+    next.checklist.iter_mut().for_each(|item| item.done = false);
+    Some(next)
+}
+
+/// 把本机和 `peer` 都已确认的计划移入已完成列表，重复的计划生成下一次。
+///
+/// 完成时间取合并后的修订时间，双方得到的记录完全相同，历史同步时不会重复。
+pub fn finish_confirmed(
+    app: &AppHandle,
+    device: Uuid,
+    peer: Uuid,
+    plans: &mut Plans,
+) -> Result<Vec<FinishedPlan>, Error> {
+    let done: Vec<Uuid> = plans
+        .plans
+        .iter()
+        .filter(|(_, plan)| plan.is_confirmed_by_both(device, peer))
+        .map(|(id, _)| *id)
+        .collect();
+    if done.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut list: FinishedPlanList = load_or_default(app, "finished-plan-list")?;
+    let mut finished = Vec::new();
+    for id in done {
+        let plan = plans.plans.remove(&id).unwrap();
+        let modified = plans.revisions.get(&id).map(|r| r.modified).unwrap_or(0);
+        let time = DateTime::from_timestamp_millis(modified as i64).unwrap_or_else(Utc::now);
+        if let Some(next) = next_occurrence(&plan, time.date_naive()) {
+            plans.plans.insert(id, next);
+        }
+        let record = FinishedPlan {
+            id: Some(id),
+            plan,
+            time,
+        };
+        list.list.push(record.clone());
+        finished.push(record);
+    }
+    store_value(app, "finished-plan-list", &list)?;
+    plans.revisions = sync::stamp_plans(app, device, &plans.plans)?;
+    store_value(
+        app,
+        sync::PLAN_DRAFTS,
+        &PlanDrafts {
+            drafts: plans.plans.clone(),
+        },
+    )?;
+    log::info!("{} plans finished by both", finished.len());
+    Ok(finished)
+}
+
+/// 本机确认计划已完成，并把计划列表设为下一条消息，碰一碰后交换双方的确认。
+#[command]
+pub async fn confirm_plan_done(app: AppHandle, plan: Uuid) -> Result<(), Error> {
+    let device = app.state::<DeviceId>().0;
+    let mut drafts: PlanDrafts = load_or_default(&app, sync::PLAN_DRAFTS)?;
+    let target = drafts
+        .drafts
+        .get_mut(&plan)
+        .ok_or(Error::PlanNotFound(plan))?;
+    if !target.confirmed_by.contains(&device) {
+        target.confirmed_by.push(device);
+        target.confirmed_by.sort();
+    }
+    let revisions = sync::stamp_plans(&app, device, &drafts.drafts)?;
+    store_value(&app, sync::PLAN_DRAFTS, &drafts)?;
+
+    let state = app.state::<Mutex<DeviceBridge>>();
+    let mut guard = state.lock().await;
+    (*guard).set_msg(Message::PlanSync(Plans {
+        selected_plan: Some(plan),
+        plans: drafts.drafts,
+        revisions,
+    }))?;
+    Ok(())
+}
+
+/// 切换子任务的完成状态，返回修改后的计划。
+#[command]
+pub fn toggle_checklist_item(app: AppHandle, plan: Uuid, item: Uuid) -> Result<Plan, Error> {
+    let mut drafts: PlanDrafts = load_or_default(&app, sync::PLAN_DRAFTS)?;
+    let target = drafts
+        .drafts
+        .get_mut(&plan)
+        .ok_or(Error::PlanNotFound(plan))?;
+    let entry = target
+        .checklist
+        .iter_mut()
+        .find(|i| i.id == item)
+        .ok_or(Error::ChecklistItemNotFound(item))?;
+    entry.done = !entry.done;
+    let updated = target.clone();
+    sync::stamp_plans(&app, app.state::<DeviceId>().0, &drafts.drafts)?;
+    store_value(&app, sync::PLAN_DRAFTS, &drafts)?;
+    Ok(updated)
+}
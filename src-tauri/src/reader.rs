@@ -0,0 +1,149 @@
+//! NFC 读卡器的生命周期：暂停、恢复、重新初始化和查询状态。
+//!
+//! 暂停有两个来源：用户手动暂停，以及 BLE 会话进行中时自动暂停以免重复触碰。
+//! 两者任一成立时读卡器都处于暂停状态，会话结束后只有用户没有手动暂停才会恢复。
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_nfc2::{Nfc2Ext, NfcError, ReaderStatus};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{models::Error, tap};
+
+#[derive(Default, Clone, Copy)]
+struct PauseState {
+    by_user: bool,
+    by_session: bool,
+}
+
+impl PauseState {
+    fn paused(&self) -> bool {
+        self.by_user || self.by_session
+    }
+}
+
+/// 托管的读卡器，保留 channel 的发送端以便重新初始化。
+pub struct NfcReader {
+    tokens: UnboundedSender<Vec<u8>>,
+    errors: UnboundedSender<NfcError>,
+    tags: UnboundedSender<Vec<u8>>,
+    pause: Mutex<PauseState>,
+    /// 最近一次初始化失败的原因
+    init_error: Mutex<Option<String>>,
+}
+
+#[derive(Serialize)]
+pub struct ReaderStatusView {
+    #[serde(flatten)]
+    pub native: ReaderStatus,
+    pub paused_by_user: bool,
+    /// BLE 会话进行中
+    pub in_session: bool,
+    pub init_error: Option<String>,
+}
+
+impl NfcReader {
+    pub fn new(
+        tokens: UnboundedSender<Vec<u8>>,
+        errors: UnboundedSender<NfcError>,
+        tags: UnboundedSender<Vec<u8>>,
+    ) -> Self {
+        Self {
+            tokens,
+            errors,
+            tags,
+            pause: Mutex::new(PauseState::default()),
+            init_error: Mutex::new(None),
+        }
+    }
+
+    /// 用新令牌初始化读卡器，失败原因会记下供 `reader_status` 查询。
+    pub fn init(&self, app: &AppHandle) -> Result<(), Error> {
+        let result = app
+            .nfc2()
+            .init_nfc_reader(
+                self.tokens.clone(),
+                self.errors.clone(),
+                self.tags.clone(),
+                &tap::issue(app),
+            )
+            .map_err(|e| Error::InitNfc(e.to_string()));
+        *self.init_error.lock().unwrap() = result.as_ref().err().map(|e| format!("{e:?}"));
+        result
+    }
+
+    /// 修改暂停状态，实际是否暂停有变化时通知原生端。
+    fn update(&self, app: &AppHandle, f: impl FnOnce(&mut PauseState)) -> Result<(), Error> {
+        let (before, after) = {
+            let mut pause = self.pause.lock().unwrap();
+            let before = pause.paused();
+            f(&mut pause);
+            (before, pause.paused())
+        };
+        match (before, after) {
+            (false, true) => app.nfc2().pause_reader(),
+            (true, false) => app.nfc2().resume_reader(),
+            _ => return Ok(()),
+        }
+        .map_err(|e| Error::InitNfc(e.to_string()))
+    }
+
+    /// 从其他途径拿到的对方令牌，如扫码配对，和 NFC 读到的一样处理。
+    ///
+    /// 处理令牌的任务已经退出时返回错误。
+    pub fn submit(&self, token: Vec<u8>) -> Result<(), Error> {
+        self.tokens.send(token).map_err(|_| Error::ReaderStopped)
+    }
+
+    /// BLE 会话开始和结束时调用。
+    ///
+    /// NFC 不支持或未打开时本来就不会读卡，会话开始时不暂停，结束时也就不必恢复，
+    /// 免得原生端每次恢复都报告 NFC 不可用。
+    pub fn set_in_session(&self, app: &AppHandle, in_session: bool) -> Result<(), Error> {
+        if in_session {
+            let status = app
+                .nfc2()
+                .reader_status()
+                .map_err(|e| Error::InitNfc(e.to_string()))?;
+            if !status.supported || !status.enabled {
+                return Ok(());
+            }
+        }
+        self.update(app, |p| p.by_session = in_session)
+    }
+}
+
+#[command]
+pub fn pause_reader(app: AppHandle) -> Result<(), Error> {
+    app.state::<NfcReader>().update(&app, |p| p.by_user = true)
+}
+
+#[command]
+pub fn resume_reader(app: AppHandle) -> Result<(), Error> {
+    app.state::<NfcReader>().update(&app, |p| p.by_user = false)
+}
+
+#[command]
+pub fn reader_status(app: AppHandle) -> Result<ReaderStatusView, Error> {
+    let reader = app.state::<NfcReader>();
+    let native = app
+        .nfc2()
+        .reader_status()
+        .map_err(|e| Error::InitNfc(e.to_string()))?;
+    let pause = *reader.pause.lock().unwrap();
+    Ok(ReaderStatusView {
+        native,
+        paused_by_user: pause.by_user,
+        in_session: pause.by_session,
+        init_error: reader.init_error.lock().unwrap().clone(),
+    })
+}
+
+/// 重新初始化读卡器，换用新的令牌。
+#[command]
+pub fn reinit_reader(app: AppHandle) -> Result<ReaderStatusView, Error> {
+    app.state::<NfcReader>().init(&app)?;
+    reader_status(app)
+}
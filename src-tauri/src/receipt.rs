@@ -0,0 +1,61 @@
+//! 回执：送达、拆开、销毁。
+//!
+//! 收方处理对方发来的内容后把回执放入 `Outbox`，下次触碰时发回；
+//! 发送方收到后按内容的 uuid 记录下来，并通知前端。
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_blep::mobile::{Message, Receipt, ReceiptKind};
+use uuid::Uuid;
+
+use crate::{
+    models::Error,
+    outbox::Outbox,
+    utils::{load_or_default, store_value},
+};
+
+const RECEIPTS: &str = "receipts";
+
+/// 发出的一项内容收到的回执。
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ItemReceipts {
+    pub delivered: Option<DateTime<Utc>>,
+    pub opened: Option<DateTime<Utc>>,
+    pub burned: Option<DateTime<Utc>>,
+}
+
+/// 收方：生成回执，等待下次触碰时发回。
+pub fn send_back(app: &AppHandle, item: Uuid, kind: ReceiptKind) -> Result<(), Error> {
+    app.state::<Outbox>().push(
+        app,
+        Message::Receipt(Receipt {
+            item,
+            kind,
+            at: Utc::now(),
+        }),
+    )
+}
+
+/// 发送方：记录收到的回执，返回这项内容的全部回执。
+pub fn record(app: &AppHandle, receipt: &Receipt) -> Result<ItemReceipts, Error> {
+    let mut receipts: HashMap<Uuid, ItemReceipts> = load_or_default(app, RECEIPTS)?;
+    let entry = receipts.entry(receipt.item).or_default();
+    let slot = match receipt.kind {
+        ReceiptKind::Delivered => &mut entry.delivered,
+        ReceiptKind::Opened => &mut entry.opened,
+        ReceiptKind::Burned => &mut entry.burned,
+    };
+    *slot = Some(receipt.at);
+    let updated = entry.clone();
+    store_value(app, RECEIPTS, &receipts)?;
+    log::info!("Receipt {:?} for {}", receipt.kind, receipt.item);
+    Ok(updated)
+}
+
+#[command]
+pub fn load_receipts(app: AppHandle) -> Result<HashMap<Uuid, ItemReceipts>, Error> {
+    load_or_default(&app, RECEIPTS)
+}
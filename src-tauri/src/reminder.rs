@@ -0,0 +1,266 @@
+//! 纪念日和里程碑提醒。
+//!
+//! 由第一次打卡的日期推算周年和满 N 天，再加上计划的目标日期和定时信件的解锁时间，
+//! 到点时发出本地通知。规则由用户设置并写入 store，已经提醒过的记下来，重启后不会重复。
+
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Days, Local, Months, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{async_runtime, command, AppHandle, Emitter, Manager};
+use tauri_plugin_notification::NotificationExt;
+use tokio::sync::Notify;
+
+use crate::{
+    clock::TrustedClock,
+    models::{Error, MailCoverList, PlanDrafts, SealedInstances},
+    sync,
+    utils::{load_or_default, store_value},
+};
+
+const RULES: &str = "reminder-rules";
+const FIRED: &str = "reminders-fired";
+
+/// 没有更早的提醒时，隔多久重新计算一次，期间新增的打卡和计划也能被算进去。
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// 只计算这段时间内的提醒。
+const HORIZON: chrono::Duration = chrono::Duration::days(366);
+/// 错过的提醒在这段时间内仍会补发，更早的不再打扰。
+const GRACE: chrono::Duration = chrono::Duration::days(1);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReminderRules {
+    pub enabled: bool,
+    /// 第一次打卡满整年时提醒
+    pub anniversaries: bool,
+    /// 第一次打卡满这些天数时提醒
+    pub milestone_days: Vec<u32>,
+    /// 计划目标日期前几天提醒，`None` 时不提醒
+    pub plan_due_days_before: Option<u32>,
+    /// 定时信件解锁时提醒
+    pub mail_unlock: bool,
+    /// 按日期的提醒在当天几点发出，本地时间
+    pub notify_hour: u32,
+}
+
+impl Default for ReminderRules {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            anniversaries: true,
+            milestone_days: vec![100, 520, 1000],
+            plan_due_days_before: Some(1),
+            mail_unlock: true,
+            notify_hour: 9,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub enum ReminderKind {
+    Anniversary,
+    Milestone,
+    PlanDue,
+    MailUnlock,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Reminder {
+    /// 唯一标识一次提醒，用于去重
+    pub key: String,
+    pub kind: ReminderKind,
+    pub at: DateTime<Utc>,
+    pub title: String,
+    pub body: String,
+}
+
+/// 规则修改后唤醒调度器重新计算。
+#[derive(Default)]
+pub struct Reminders {
+    wake: Notify,
+}
+
+/// 本地时间某天的某个整点。
+fn at_hour(date: NaiveDate, hour: u32) -> Option<DateTime<Utc>> {
+    Local
+        .from_local_datetime(&date.and_hms_opt(hour, 0, 0)?)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// 计算 `[now - GRACE, now + HORIZON]` 内的全部提醒，按时间排序。
+fn upcoming(
+    app: &AppHandle,
+    rules: &ReminderRules,
+    now: DateTime<Utc>,
+) -> Result<Vec<Reminder>, Error> {
+    let mut reminders = Vec::new();
+    if !rules.enabled {
+        return Ok(reminders);
+    }
+    let hour = rules.notify_hour;
+
+    let sealed: SealedInstances = load_or_default(app, "sealed-instances")?;
+    if let Some(first) = sealed.instances.iter().map(|i| i.time).min() {
+        let first = first.with_timezone(&Local).date_naive();
+        if rules.anniversaries {
+            for years in 1.. {
+                let Some(at) = first
+                    .checked_add_months(Months::new(12 * years))
+                    .and_then(|d| at_hour(d, hour))
+                else {
+                    break;
+                };
+                if at > now + HORIZON {
+                    break;
+                }
+                reminders.push(Reminder {
+                    key: format!("anniversary:{years}"),
+                    kind: ReminderKind::Anniversary,
+                    at,
+                    title: format!("{years} 周年"),
+                    body: format!("第一次打卡已经 {years} 年了"),
+                });
+            }
+        }
+        for &days in &rules.milestone_days {
+            let Some(at) = first
+                .checked_add_days(Days::new(days as u64))
+                .and_then(|d| at_hour(d, hour))
+            else {
+                continue;
+            };
+            reminders.push(Reminder {
+                key: format!("milestone:{days}"),
+                kind: ReminderKind::Milestone,
+                at,
+                title: format!("{days} 天"),
+                body: format!("第一次打卡已经 {days} 天了"),
+            });
+        }
+    }
+
+    if let Some(before) = rules.plan_due_days_before {
+        let plans: PlanDrafts = load_or_default(app, sync::PLAN_DRAFTS)?;
+        for (id, plan) in &plans.drafts {
+            let Some(due) = plan.due else {
+                continue;
+            };
+            let Some(at) = due
+                .checked_sub_days(Days::new(before as u64))
+                .and_then(|d| at_hour(d, hour))
+            else {
+                continue;
+            };
+            let body = match before {
+                0 => "就是今天".to_string(),
+                n => format!("还有 {n} 天"),
+            };
+            reminders.push(Reminder {
+                key: format!("plan:{id}:{due}"),
+                kind: ReminderKind::PlanDue,
+                at,
+                title: plan.title.clone(),
+                body,
+            });
+        }
+    }
+
+    if rules.mail_unlock {
+        let covers: MailCoverList = load_or_default(app, sync::INBOX_MAIL_LIST)?;
+        for (id, cover) in &covers.mails {
+            let Some(unlock_at) = cover.unlock_at else {
+                continue;
+            };
+            reminders.push(Reminder {
+                key: format!("mail:{id}"),
+                kind: ReminderKind::MailUnlock,
+                at: unlock_at,
+                title: "信件可以拆开了".to_string(),
+                body: cover.cover.clone(),
+            });
+        }
+    }
+
+    reminders.retain(|r| r.at >= now - GRACE && r.at <= now + HORIZON);
+    reminders.sort_by_key(|r| r.at);
+    Ok(reminders)
+}
+
+/// 发出到点且没有提醒过的通知，返回距下一次提醒的时长。
+fn fire_due(app: &AppHandle) -> Result<Duration, Error> {
+    let now = app.state::<TrustedClock>().now();
+    let rules: ReminderRules = load_or_default(app, RULES)?;
+    let mut fired: HashMap<String, DateTime<Utc>> = load_or_default(app, FIRED)?;
+    let before = fired.len();
+    fired.retain(|_, at| *at >= now - HORIZON);
+    let mut changed = fired.len() != before;
+
+    let mut next_check = CHECK_INTERVAL;
+    for reminder in upcoming(app, &rules, now)? {
+        if reminder.at > now {
+            next_check = next_check.min((reminder.at - now).to_std().unwrap_or_default());
+            break;
+        }
+        if fired.contains_key(&reminder.key) {
+            continue;
+        }
+        app.notification()
+            .builder()
+            .title(&reminder.title)
+            .body(&reminder.body)
+            .show()
+            .map_err(|e| Error::Notify(e.to_string()))?;
+        log::info!("Reminder {} fired", reminder.key);
+        app.emit("reminder-fired", &reminder).unwrap();
+        fired.insert(reminder.key, reminder.at);
+        changed = true;
+    }
+    if changed {
+        store_value(app, FIRED, &fired)?;
+    }
+    Ok(next_check)
+}
+
+/// 启动提醒调度器。
+pub fn watch(app: AppHandle) {
+    async_runtime::spawn(async move {
+        loop {
+            let next_check = fire_due(&app).unwrap_or_else(|e| {
+                app.emit("err", e).unwrap();
+                CHECK_INTERVAL
+            });
+            let reminders = app.state::<Reminders>();
+            // 超时即到了下一次提醒，被唤醒则是规则变了，都重新计算
+            let _ = tokio::time::timeout(next_check, reminders.wake.notified()).await;
+        }
+    });
+}
+
+#[command]
+pub fn load_reminder_rules(app: AppHandle) -> Result<ReminderRules, Error> {
+    load_or_default(&app, RULES)
+}
+
+#[command]
+pub fn store_reminder_rules(app: AppHandle, rules: ReminderRules) -> Result<(), Error> {
+    if rules.notify_hour > 23 {
+        return Err(Error::InvalidReminderRule("notify_hour".to_string()));
+    }
+    if rules.milestone_days.contains(&0) {
+        return Err(Error::InvalidReminderRule("milestone_days".to_string()));
+    }
+    store_value(&app, RULES, &rules)?;
+    app.state::<Reminders>().wake.notify_one();
+    Ok(())
+}
+
+/// 接下来的提醒，供设置页预览。
+#[command]
+pub fn upcoming_reminders(app: AppHandle) -> Result<Vec<Reminder>, Error> {
+    let now = app.state::<TrustedClock>().now();
+    let rules: ReminderRules = load_or_default(&app, RULES)?;
+    let mut reminders = upcoming(&app, &rules, now)?;
+    reminders.retain(|r| r.at > now);
+    Ok(reminders)
+}
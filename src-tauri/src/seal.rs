@@ -0,0 +1,323 @@
+//! 打卡“保存这一刻”：双方共同提交、共同签名的记录。
+//!
+//! 1. 双方各自设置打卡内容，触碰时交换 `SealProposal`；
+//! 2. 内容一致时，双方由两份提议推得完全相同的 `SealRecord`，各自签名后用 `Message::SealSign` 发给对方；
+//! 3. 收到对方的签名并验证通过后，双方存下相同的 `Instance`，id 也相同。
+//!
+//! 双方都可以附带位置、天气、照片等上下文，经过校验后一并签名。照片作为附件在之后的触碰中传给对方。
+
+use std::sync::Mutex as StdMutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{async_runtime, command, AppHandle, Emitter, Manager};
+use tauri_plugin_blep::mobile::{Message, ReceiptKind};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    attachment::{self, AttachmentMeta},
+    ble::DeviceBridge,
+    identity::{self, Identity},
+    models::{Error, Instance, SealedInstances},
+    outbox::Outbox,
+    receipt,
+    utils::{load_or_default, store_value},
+};
+
+/// 坐标保留的小数位数，约 1 公里。
+const LOCATION_PRECISION: f64 = 100.0;
+/// 天气描述的最大字符数。
+const MAX_WEATHER_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Weather {
+    /// 天气描述，如“晴”
+    pub condition: String,
+    /// 摄氏度
+    pub temperature: Option<f32>,
+}
+
+/// 打卡时的上下文，每一项都是可选的。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct SealContext {
+    /// 粗略位置
+    pub location: Option<Location>,
+    pub weather: Option<Weather>,
+    /// 照片，需先用 `add_attachment` 加入
+    pub photo: Option<AttachmentMeta>,
+}
+
+impl SealContext {
+    /// 校验前端传来的上下文，位置只保留到约 1 公里。
+    fn validate(mut self, app: &AppHandle) -> Result<Self, Error> {
+        if let Some(loc) = &mut self.location {
+            if !(-90.0..=90.0).contains(&loc.latitude) || !(-180.0..=180.0).contains(&loc.longitude)
+            {
+                return Err(Error::InvalidSealContext("location".to_string()));
+            }
+            loc.latitude = (loc.latitude * LOCATION_PRECISION).round() / LOCATION_PRECISION;
+            loc.longitude = (loc.longitude * LOCATION_PRECISION).round() / LOCATION_PRECISION;
+        }
+        if let Some(weather) = &self.weather {
+            let len = weather.condition.chars().count();
+            let temperature_ok = weather
+                .temperature
+                .is_none_or(|t| (-90.0..=60.0).contains(&t));
+            if len == 0 || len > MAX_WEATHER_LEN || !temperature_ok {
+                return Err(Error::InvalidSealContext("weather".to_string()));
+            }
+        }
+        if let Some(photo) = &self.photo {
+            if !photo.mime.starts_with("image/") {
+                return Err(Error::InvalidSealContext("photo".to_string()));
+            }
+            attachment::offer(app, std::slice::from_ref(photo))?;
+        }
+        Ok(self)
+    }
+
+    /// 合并双方的上下文，每一项优先取 `first` 的。
+    fn merge(first: &Self, second: &Self) -> Self {
+        Self {
+            location: first.location.clone().or_else(|| second.location.clone()),
+            weather: first.weather.clone().or_else(|| second.weather.clone()),
+            photo: first.photo.clone().or_else(|| second.photo.clone()),
+        }
+    }
+}
+
+/// 一方的打卡提议，作为 `Message::Seal` 的内容。
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SealProposal {
+    pub id: Uuid,
+    pub content: String,
+    pub device: Uuid,
+    /// base64 编码的 ed25519 公钥
+    pub public_key: String,
+    pub proposed_at: DateTime<Utc>,
+    pub context: SealContext,
+}
+
+/// 双方共同签名的记录。字段顺序固定，序列化结果即为签名的内容。
+#[derive(Serialize, Clone)]
+pub struct SealRecord {
+    pub id: Uuid,
+    pub content: String,
+    /// 双方的设备 uuid，从小到大
+    pub devices: [Uuid; 2],
+    pub time: DateTime<Utc>,
+    pub context: SealContext,
+}
+
+/// 一方对记录的签名，作为 `Message::SealSign` 的内容，也随 `Instance` 保存。
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SealSignature {
+    pub record: Uuid,
+    pub device: Uuid,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl SealRecord {
+    /// 由双方的提议推得记录，双方得到的结果相同。
+    ///
+    /// 时间取较晚的提议时间，即双方都准备好的时刻；上下文优先取 uuid 较小一方的。
+    fn agree(a: &SealProposal, b: &SealProposal) -> Self {
+        let (first, second) = if a.device < b.device { (a, b) } else { (b, a) };
+        let mut seed = first.id.as_bytes().to_vec();
+        seed.extend_from_slice(second.id.as_bytes());
+        Self {
+            id: Uuid::new_v5(&Uuid::NAMESPACE_OID, &seed),
+            content: first.content.clone(),
+            devices: [first.device, second.device],
+            time: first.proposed_at.max(second.proposed_at),
+            context: SealContext::merge(&first.context, &second.context),
+        }
+    }
+
+    fn from_instance(instance: &Instance) -> Option<Self> {
+        Some(Self {
+            id: instance.id?,
+            content: instance.instance.clone(),
+            devices: instance.devices.clone().try_into().ok()?,
+            time: instance.time,
+            context: instance.context.clone(),
+        })
+    }
+
+    fn canonical(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+struct PendingSeal {
+    record: SealRecord,
+    peer: Uuid,
+    peer_key: String,
+    mine: SealSignature,
+}
+
+/// 进行中的打卡。
+#[derive(Default)]
+pub struct SealSession {
+    mine: StdMutex<Option<SealProposal>>,
+    pending: StdMutex<Option<PendingSeal>>,
+}
+
+/// 设置打卡内容和上下文，等待触碰。
+#[command]
+pub async fn set_seal_msg(
+    app: AppHandle,
+    msg: String,
+    context: Option<SealContext>,
+) -> Result<(), Error> {
+    let context = context.unwrap_or_default().validate(&app)?;
+    let identity = app.state::<Identity>();
+    let proposal = SealProposal {
+        id: Uuid::new_v4(),
+        content: msg,
+        device: identity.device,
+        public_key: identity.public_key(),
+        proposed_at: Utc::now(),
+        context,
+    };
+    let session = app.state::<SealSession>();
+    *session.mine.lock().unwrap() = Some(proposal.clone());
+    *session.pending.lock().unwrap() = None;
+
+    let state = app.state::<Mutex<DeviceBridge>>();
+    let mut guard = state.lock().await;
+    (*guard).set_msg(Message::Seal(serde_json::to_string(&proposal).unwrap()))?;
+    Ok(())
+}
+
+/// 收到对方的提议，内容一致时签名并返回本方的签名。
+pub fn receive_proposal(app: &AppHandle, proposal: &str) -> Result<SealSignature, Error> {
+    let theirs: SealProposal =
+        serde_json::from_str(proposal).map_err(|e| Error::Load(e.to_string()))?;
+    receipt::send_back(app, theirs.id, ReceiptKind::Delivered)?;
+
+    let session = app.state::<SealSession>();
+    let mine = session
+        .mine
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or(Error::NoSealPending)?;
+    if mine.content != theirs.content {
+        return Err(Error::SealMismatch);
+    }
+    let record = SealRecord::agree(&mine, &theirs);
+    // 记录中的照片是对方的时，等下次触碰时请求
+    attachment::expect(app, record.context.photo.as_slice())?;
+    let identity = app.state::<Identity>();
+    let signature = SealSignature {
+        record: record.id,
+        device: identity.device,
+        public_key: identity.public_key(),
+        signature: identity.sign(&record.canonical()),
+    };
+    *session.pending.lock().unwrap() = Some(PendingSeal {
+        record,
+        peer: theirs.device,
+        peer_key: theirs.public_key,
+        mine: signature.clone(),
+    });
+    Ok(signature)
+}
+
+/// 立即把签名发给对方，发送失败时留到下次触碰。
+pub fn send_signature(app: AppHandle, signature: SealSignature) {
+    async_runtime::spawn(async move {
+        let msg = Message::SealSign(serde_json::to_string(&signature).unwrap());
+        let state = app.state::<Mutex<DeviceBridge>>();
+        let mut guard = state.lock().await;
+        if let Err(e) = (*guard).send_control(msg.clone()).await {
+            log::warn!("Failed to send seal signature: {e:?}");
+            app.state::<Outbox>()
+                .push(&app, msg)
+                .unwrap_or_else(|e| app.emit("err", e).unwrap());
+        }
+    });
+}
+
+/// 收到对方的签名，验证后存下双方相同的记录。
+pub fn receive_signature(app: &AppHandle, signature: &str) -> Result<Instance, Error> {
+    let theirs: SealSignature =
+        serde_json::from_str(signature).map_err(|e| Error::Load(e.to_string()))?;
+    let session = app.state::<SealSession>();
+    let pending = session
+        .pending
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or(Error::NoSealPending)?;
+    if theirs.record != pending.record.id
+        || theirs.device != pending.peer
+        || theirs.public_key != pending.peer_key
+    {
+        return Err(Error::BadSignature);
+    }
+    identity::verify(
+        &theirs.public_key,
+        &pending.record.canonical(),
+        &theirs.signature,
+    )?;
+
+    let mut signatures = vec![pending.mine, theirs];
+    signatures.sort_by_key(|s| s.device);
+    let record = pending.record;
+    let instance = Instance {
+        id: Some(record.id),
+        instance: record.content,
+        time: record.time,
+        context: record.context,
+        devices: record.devices.to_vec(),
+        signatures,
+    };
+    let mut sealed: SealedInstances = load_or_default(app, "sealed-instances")?;
+    if !sealed.instances.iter().any(|i| i.id == instance.id) {
+        sealed.instances.push(instance.clone());
+        store_value(app, "sealed-instances", &sealed)?;
+    }
+    log::info!("Seal {} committed", record.id);
+    Ok(instance)
+}
+
+/// 验证一条打卡记录的双方签名。
+pub fn verify(instance: &Instance) -> Result<(), Error> {
+    let record = SealRecord::from_instance(instance).ok_or(Error::BadSignature)?;
+    if instance.signatures.len() != 2 {
+        return Err(Error::BadSignature);
+    }
+    for (device, signature) in record.devices.iter().zip(&instance.signatures) {
+        if signature.device != *device || signature.record != record.id {
+            return Err(Error::BadSignature);
+        }
+        identity::verify(
+            &signature.public_key,
+            &record.canonical(),
+            &signature.signature,
+        )?;
+    }
+    Ok(())
+}
+
+/// 验证打卡记录是否由双方共同签名，旧版本的记录没有签名。
+#[command]
+pub fn verify_seal(app: AppHandle, id: Uuid) -> Result<bool, Error> {
+    let sealed: SealedInstances = load_or_default(&app, "sealed-instances")?;
+    let instance = sealed
+        .instances
+        .iter()
+        .find(|i| i.id == Some(id))
+        .ok_or(Error::SealNotFound(id))?;
+    Ok(verify(instance).is_ok())
+}
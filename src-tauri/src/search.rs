@@ -0,0 +1,403 @@
+//! 全文搜索：信件、打卡、计划和草稿。
+//!
+//! 索引只在内存中，启动时由 store 建立，之后每次通过 `store_value` 写入时按 key 更新对应的部分。
+//! 中文等没有空格分词的文字按单字和相邻两字切分，其他文字按单词切分，全部离线完成。
+//! 定时信件在解锁前也会被索引，但搜索结果中不会出现，到时间后自然可以搜到。
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
+
+use crate::{
+    mailbox,
+    models::{
+        DisposableDrafts, Error, FinishedPlanList, MailCoverList, MailInner, PlanDrafts,
+        SealedInstances,
+    },
+    sync,
+    utils::legacy_id,
+};
+
+/// 标题、封面等短字段的权重。
+const TITLE_WEIGHT: f32 = 2.0;
+const BODY_WEIGHT: f32 = 1.0;
+/// 单字的权重，低于相邻两字。
+const UNIGRAM_WEIGHT: f32 = 0.5;
+const DEFAULT_LIMIT: usize = 20;
+const SNIPPET_LEN: usize = 40;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SearchKind {
+    /// 收发的信件和信件草稿
+    Mail,
+    Seal,
+    Plan,
+    FinishedPlan,
+    Disposable,
+}
+
+#[derive(Deserialize, Default)]
+pub struct SearchFilters {
+    /// 为空时搜索全部类型
+    #[serde(default)]
+    pub kinds: Vec<SearchKind>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub kind: SearchKind,
+    pub id: Uuid,
+    pub score: f32,
+    pub snippet: String,
+    pub time: Option<DateTime<Utc>>,
+}
+
+type DocKey = (SearchKind, Uuid);
+
+/// 一个字段，同一条记录的字段可能来自 store 中不同的 key。
+struct Entry {
+    key: DocKey,
+    text: String,
+    weight: f32,
+    time: Option<DateTime<Utc>>,
+    terms: HashMap<String, f32>,
+}
+
+impl Entry {
+    fn new(key: DocKey, text: &str, weight: f32, time: Option<DateTime<Utc>>) -> Self {
+        let mut terms = HashMap::new();
+        for (term, w) in tokenize(text) {
+            *terms.entry(term).or_default() += w * weight;
+        }
+        Self {
+            key,
+            text: text.to_string(),
+            weight,
+            time,
+            terms,
+        }
+    }
+}
+
+#[derive(Default)]
+struct IndexState {
+    /// store 的 key 到由它得到的字段
+    sources: HashMap<String, Vec<Entry>>,
+    /// 词到包含它的记录和词频
+    postings: HashMap<String, HashMap<DocKey, f32>>,
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    state: Mutex<IndexState>,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}')
+}
+
+/// 切分文字，返回词和权重。中日韩文字取单字和相邻两字，其他取单词。
+fn tokenize(text: &str) -> Vec<(String, f32)> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut run: Vec<char> = Vec::new();
+    let flush_word = |word: &mut String, tokens: &mut Vec<(String, f32)>| {
+        if !word.is_empty() {
+            tokens.push((std::mem::take(word), 1.0));
+        }
+    };
+    let flush_run = |run: &mut Vec<char>, tokens: &mut Vec<(String, f32)>| {
+        for c in run.iter() {
+            tokens.push((c.to_string(), UNIGRAM_WEIGHT));
+        }
+        for pair in run.windows(2) {
+            tokens.push((pair.iter().collect(), 1.0));
+        }
+        run.clear();
+    };
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if is_cjk(c) {
+            flush_word(&mut word, &mut tokens);
+            run.push(c);
+        } else if c.is_alphanumeric() {
+            flush_run(&mut run, &mut tokens);
+            word.push(c);
+        } else {
+            flush_word(&mut word, &mut tokens);
+            flush_run(&mut run, &mut tokens);
+        }
+    }
+    flush_word(&mut word, &mut tokens);
+    flush_run(&mut run, &mut tokens);
+    tokens
+}
+
+/// 查询的词：中日韩文字一个字时取单字，多个字时只取相邻两字。
+fn query_terms(query: &str) -> HashSet<String> {
+    let tokens = tokenize(query);
+    let has_bigram = tokens
+        .iter()
+        .any(|(t, _)| t.chars().count() == 2 && t.chars().all(is_cjk));
+    tokens
+        .into_iter()
+        .filter(|(t, w)| !(has_bigram && *w == UNIGRAM_WEIGHT && t.chars().all(is_cjk)))
+        .map(|(t, _)| t)
+        .collect()
+}
+
+/// 由 store 中的一项得到字段，与搜索无关的 key 返回 `None`。
+fn entries(key: &str, value: &Value) -> Option<Vec<Entry>> {
+    let parse_err = |e: serde_json::Error| log::warn!("Failed to index {key}: {e}");
+    let mut out = Vec::new();
+    match key {
+        "sealed-instances" => {
+            let sealed: SealedInstances = serde_json::from_value(value.clone())
+                .map_err(parse_err)
+                .ok()?;
+            for i in &sealed.instances {
+                let id =
+                    i.id.unwrap_or_else(|| legacy_id(&[&i.instance, &i.time.to_rfc3339()]));
+                out.push(Entry::new(
+                    (SearchKind::Seal, id),
+                    &i.instance,
+                    BODY_WEIGHT,
+                    Some(i.time),
+                ));
+            }
+        }
+        "finished-plan-list" => {
+            let list: FinishedPlanList = serde_json::from_value(value.clone())
+                .map_err(parse_err)
+                .ok()?;
+            for f in &list.list {
+                let id =
+                    f.id.unwrap_or_else(|| legacy_id(&[&f.plan.title, &f.time.to_rfc3339()]));
+                let key = (SearchKind::FinishedPlan, id);
+                out.push(Entry::new(key, &f.plan.title, TITLE_WEIGHT, Some(f.time)));
+                out.push(Entry::new(key, &f.plan.body, BODY_WEIGHT, Some(f.time)));
+            }
+        }
+        sync::PLAN_DRAFTS => {
+            let drafts: PlanDrafts = serde_json::from_value(value.clone())
+                .map_err(parse_err)
+                .ok()?;
+            for (id, plan) in &drafts.drafts {
+                let key = (SearchKind::Plan, *id);
+                out.push(Entry::new(key, &plan.title, TITLE_WEIGHT, None));
+                out.push(Entry::new(key, &plan.body, BODY_WEIGHT, None));
+                for item in &plan.checklist {
+                    out.push(Entry::new(key, &item.text, BODY_WEIGHT, None));
+                }
+            }
+        }
+        sync::INBOX_MAIL_LIST | sync::SENT_MAIL_LIST | "mail-drafts-cover-list" => {
+            let covers: MailCoverList = serde_json::from_value(value.clone())
+                .map_err(parse_err)
+                .ok()?;
+            for (id, cover) in &covers.mails {
+                out.push(Entry::new(
+                    (SearchKind::Mail, *id),
+                    &cover.cover,
+                    TITLE_WEIGHT,
+                    Some(cover.timestamp),
+                ));
+            }
+        }
+        "disposable-drafts" => {
+            let drafts: DisposableDrafts = serde_json::from_value(value.clone())
+                .map_err(parse_err)
+                .ok()?;
+            for d in &drafts.drafts {
+                let key = (SearchKind::Disposable, legacy_id(&[&d.title, &d.body]));
+                out.push(Entry::new(key, &d.title, TITLE_WEIGHT, None));
+                out.push(Entry::new(key, &d.body, BODY_WEIGHT, None));
+            }
+        }
+        _ => {
+            // 信件正文 `mail-{uuid}`，草稿和收发的信件共用
+            let id = Uuid::parse_str(key.strip_prefix("mail-")?).ok()?;
+            let inner: MailInner = serde_json::from_value(value.clone())
+                .map_err(parse_err)
+                .ok()?;
+            out.push(Entry::new(
+                (SearchKind::Mail, id),
+                &inner.title,
+                TITLE_WEIGHT,
+                None,
+            ));
+            out.push(Entry::new(
+                (SearchKind::Mail, id),
+                &inner.body,
+                BODY_WEIGHT,
+                None,
+            ));
+        }
+    }
+    Some(out)
+}
+
+fn snippet(text: &str, query: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let lower = text.to_lowercase();
+    let start = lower
+        .find(&query.to_lowercase())
+        .map(|byte| lower[..byte].chars().count())
+        .unwrap_or(0)
+        .saturating_sub(SNIPPET_LEN / 4);
+    chars.iter().skip(start).take(SNIPPET_LEN).collect()
+}
+
+impl SearchIndex {
+    /// 由 store 中现有的内容建立索引。
+    pub fn build(app: &AppHandle) -> Result<Self, Error> {
+        let index = Self::default();
+        let store = app.store("store.json").map_err(Into::<Error>::into)?;
+        for (key, value) in store.entries() {
+            index.update(&key, Some(&value));
+        }
+        Ok(index)
+    }
+
+    /// 重新索引 store 中的一项，`value` 为 `None` 表示已被删除。
+    pub fn update(&self, key: &str, value: Option<&Value>) {
+        let new = match value {
+            Some(v) => match entries(key, v) {
+                Some(e) => e,
+                None => return,
+            },
+            None => Vec::new(),
+        };
+        let mut state = self.state.lock().unwrap();
+        let IndexState { sources, postings } = &mut *state;
+        for entry in sources.remove(key).unwrap_or_default() {
+            for (term, w) in &entry.terms {
+                if let Some(docs) = postings.get_mut(term) {
+                    if let Some(tf) = docs.get_mut(&entry.key) {
+                        *tf -= w;
+                        if *tf <= f32::EPSILON {
+                            docs.remove(&entry.key);
+                        }
+                    }
+                    if docs.is_empty() {
+                        postings.remove(term);
+                    }
+                }
+            }
+        }
+        for entry in &new {
+            for (term, w) in &entry.terms {
+                *postings
+                    .entry(term.clone())
+                    .or_default()
+                    .entry(entry.key)
+                    .or_default() += w;
+            }
+        }
+        if !new.is_empty() {
+            sources.insert(key.to_string(), new);
+        }
+    }
+
+    /// `visible` 决定一条记录能否出现在结果中，在截断前过滤，不影响返回的数量。
+    fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        visible: impl Fn(&DocKey) -> bool,
+    ) -> Vec<SearchHit> {
+        let terms = query_terms(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let state = self.state.lock().unwrap();
+        let total = state
+            .postings
+            .values()
+            .flat_map(|docs| docs.keys())
+            .collect::<HashSet<_>>()
+            .len() as f32;
+
+        // 每个词都要出现
+        let mut scores: Option<HashMap<DocKey, f32>> = None;
+        for term in &terms {
+            let Some(docs) = state.postings.get(term) else {
+                return Vec::new();
+            };
+            let idf = (1.0 + total / docs.len() as f32).ln();
+            let next = docs
+                .iter()
+                .filter(|(key, _)| scores.as_ref().is_none_or(|s| s.contains_key(*key)))
+                .map(|(key, tf)| {
+                    let prev = scores.as_ref().and_then(|s| s.get(key)).copied();
+                    (*key, prev.unwrap_or(0.0) + tf * idf)
+                })
+                .collect();
+            scores = Some(next);
+        }
+
+        let mut best: HashMap<DocKey, (&Entry, Option<DateTime<Utc>>)> = HashMap::new();
+        for entry in state.sources.values().flatten() {
+            let slot = best.entry(entry.key).or_insert((entry, entry.time));
+            if entry.weight > slot.0.weight && !entry.text.is_empty() {
+                slot.0 = entry;
+            }
+            slot.1 = slot.1.or(entry.time);
+        }
+
+        let mut hits: Vec<SearchHit> = scores
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|((kind, _), _)| filters.kinds.is_empty() || filters.kinds.contains(kind))
+            .filter(|(key, _)| visible(key))
+            .filter_map(|(key, score)| {
+                let (entry, time) = best.get(&key)?;
+                let in_range = match (filters.from, filters.to) {
+                    (None, None) => true,
+                    (from, to) => time
+                        .is_some_and(|t| from.is_none_or(|f| t >= f) && to.is_none_or(|u| t <= u)),
+                };
+                in_range.then(|| SearchHit {
+                    kind: key.0,
+                    id: key.1,
+                    score,
+                    snippet: snippet(&entry.text, query),
+                    time: *time,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(filters.limit.unwrap_or(DEFAULT_LIMIT));
+        hits
+    }
+}
+
+/// 搜索，按相关度从高到低返回。未解锁的定时信件不会出现。
+#[command]
+pub fn search(
+    app: AppHandle,
+    query: String,
+    filters: Option<SearchFilters>,
+) -> Result<Vec<SearchHit>, Error> {
+    let filters = filters.unwrap_or_default();
+    Ok(app
+        .state::<SearchIndex>()
+        .search(&query, &filters, |(kind, id)| {
+            *kind != SearchKind::Mail || mailbox::ensure_unlocked(&app, *id).is_ok()
+        }))
+}
@@ -0,0 +1,276 @@
+//! 统计：记录每一次成功的收发，在本机汇总成关系洞察。
+//!
+//! 触碰次数和信件往来按天和对方累计；打卡和完成的计划双方会同步，直接从已有的记录统计。
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+use tauri_plugin_blep::mobile::Message;
+use uuid::Uuid;
+
+use crate::{
+    clock::TrustedClock,
+    models::{DeviceId, Error, FinishedPlanList, MessageType, SealedInstances},
+    utils::{load_or_default, store_value},
+};
+
+const STATS: &str = "stats-daily";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// 某天与某一方之间的累计收发。
+#[derive(Serialize, Deserialize, Default)]
+struct DayCounts {
+    taps: u32,
+    letters_sent: u32,
+    letters_received: u32,
+    first_tap: Option<DateTime<Utc>>,
+    last_tap: Option<DateTime<Utc>>,
+}
+
+/// 按本地日期和对方累计的收发，对方未知时记在 nil 下。
+///
+/// 每次收发只更新一项，大小随天数增长而不随消息数增长。
+#[derive(Serialize, Deserialize, Default)]
+struct Daily(BTreeMap<NaiveDate, BTreeMap<Uuid, DayCounts>>);
+
+impl Daily {
+    fn add(
+        &mut self,
+        at: DateTime<Utc>,
+        direction: Direction,
+        kind: &MessageType,
+        peer: Option<Uuid>,
+    ) {
+        let day = at.with_timezone(&Local).date_naive();
+        let counts = self
+            .0
+            .entry(day)
+            .or_default()
+            .entry(peer.unwrap_or_default())
+            .or_default();
+        if direction == Direction::Sent {
+            counts.taps += 1;
+            counts.first_tap = Some(counts.first_tap.map_or(at, |t| t.min(at)));
+            counts.last_tap = counts.last_tap.max(Some(at));
+        }
+        if matches!(kind, MessageType::Mail) {
+            match direction {
+                Direction::Sent => counts.letters_sent += 1,
+                Direction::Received => counts.letters_received += 1,
+            }
+        }
+    }
+}
+
+/// 记录一次收发，控制消息不计入。
+pub fn record(
+    app: &AppHandle,
+    direction: Direction,
+    msg: &Message,
+    peer: Option<Uuid>,
+) -> Result<(), Error> {
+    if msg.is_control() {
+        return Ok(());
+    }
+    let mut daily: Daily = load_or_default(app, STATS)?;
+    let at = app.state::<TrustedClock>().now();
+    daily.add(at, direction, &MessageType::from(msg), peer);
+    store_value(app, STATS, &daily)
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum Period {
+    #[default]
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// 日期所在周期的第一天，周从周一开始。
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Week => date.week(Weekday::Mon).first_day(),
+            Period::Month => date.with_day(1).unwrap(),
+            Period::Year => date.with_ordinal(1).unwrap(),
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct Counts {
+    /// 本机发出的触碰次数，每次触碰发出一条主消息
+    pub taps: u32,
+    pub letters_sent: u32,
+    pub letters_received: u32,
+    pub seals: u32,
+    pub plans_completed: u32,
+}
+
+#[derive(Serialize)]
+pub struct PeriodStats {
+    pub start: NaiveDate,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+#[derive(Serialize)]
+pub struct PeerStats {
+    pub peer: Uuid,
+    pub last_tap: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+#[derive(Serialize)]
+pub struct Insights {
+    pub first_tap: Option<DateTime<Utc>>,
+    /// 距第一次触碰的天数
+    pub days_since_first_tap: Option<i64>,
+    /// 截至今天或昨天、连续每天打卡的天数
+    pub current_streak: u32,
+    pub longest_streak: u32,
+    pub total: Counts,
+    /// 按周期从早到晚
+    pub periods: Vec<PeriodStats>,
+    pub peers: Vec<PeerStats>,
+}
+
+/// 一条统计对各项计数的贡献。
+enum Item {
+    Tap,
+    LetterSent,
+    LetterReceived,
+    Seal,
+    PlanCompleted,
+}
+
+impl Counts {
+    fn add(&mut self, item: &Item, n: u32) {
+        match item {
+            Item::Tap => self.taps += n,
+            Item::LetterSent => self.letters_sent += n,
+            Item::LetterReceived => self.letters_received += n,
+            Item::Seal => self.seals += n,
+            Item::PlanCompleted => self.plans_completed += n,
+        }
+    }
+}
+
+/// 打卡日期中最长的连续天数，以及截至 `today` 或前一天的连续天数。
+fn streaks(mut days: Vec<NaiveDate>, today: NaiveDate) -> (u32, u32) {
+    days.sort();
+    days.dedup();
+    let (mut longest, mut run) = (0, 0);
+    let mut last: Option<NaiveDate> = None;
+    for day in &days {
+        run = match last {
+            Some(prev) if prev.succ_opt() == Some(*day) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        last = Some(*day);
+    }
+    let current = match last {
+        Some(day) if day == today || day.succ_opt() == Some(today) => run,
+        _ => 0,
+    };
+    (current, longest)
+}
+
+/// 按周期和对方汇总统计，可只看某一方。
+#[command]
+pub fn insights(
+    app: AppHandle,
+    period: Option<Period>,
+    peer: Option<Uuid>,
+) -> Result<Insights, Error> {
+    let period = period.unwrap_or_default();
+    let now = app.state::<TrustedClock>().now();
+    let me = app.state::<DeviceId>().0;
+
+    let mut items: Vec<(NaiveDate, Option<Uuid>, Item, u32)> = Vec::new();
+    // 各方每天第一次和最后一次触碰的时间
+    let mut taps: Vec<(Option<Uuid>, DateTime<Utc>, DateTime<Utc>)> = Vec::new();
+    for (day, counts) in load_or_default::<Daily>(&app, STATS)?.0 {
+        for (p, c) in counts {
+            let p = (!p.is_nil()).then_some(p);
+            items.push((day, p, Item::Tap, c.taps));
+            items.push((day, p, Item::LetterSent, c.letters_sent));
+            items.push((day, p, Item::LetterReceived, c.letters_received));
+            if let (Some(first), Some(last)) = (c.first_tap, c.last_tap) {
+                taps.push((p, first, last));
+            }
+        }
+    }
+    let local_day = |at: DateTime<Utc>| at.with_timezone(&Local).date_naive();
+    let other = |devices: &[Uuid]| devices.iter().find(|d| **d != me).copied();
+    let sealed: SealedInstances = load_or_default(&app, "sealed-instances")?;
+    for i in &sealed.instances {
+        items.push((local_day(i.time), other(&i.devices), Item::Seal, 1));
+    }
+    let finished: FinishedPlanList = load_or_default(&app, "finished-plan-list")?;
+    for f in &finished.list {
+        items.push((
+            local_day(f.time),
+            other(&f.plan.confirmed_by),
+            Item::PlanCompleted,
+            1,
+        ));
+    }
+    items.retain(|(_, p, _, n)| *n > 0 && (peer.is_none() || *p == peer));
+    taps.retain(|(p, _, _)| peer.is_none() || *p == peer);
+
+    let mut total = Counts::default();
+    let mut periods: BTreeMap<NaiveDate, Counts> = BTreeMap::new();
+    let mut peers: HashMap<Uuid, PeerStats> = HashMap::new();
+    let mut seal_days = Vec::new();
+    for (day, p, item, n) in &items {
+        total.add(item, *n);
+        periods.entry(period.start(*day)).or_default().add(item, *n);
+        if let Some(p) = p {
+            peers
+                .entry(*p)
+                .or_insert_with(|| PeerStats {
+                    peer: *p,
+                    last_tap: None,
+                    counts: Counts::default(),
+                })
+                .counts
+                .add(item, *n);
+        }
+        if matches!(item, Item::Seal) {
+            seal_days.push(*day);
+        }
+    }
+    let first_tap = taps.iter().map(|(_, first, _)| *first).min();
+    for (p, _, last) in &taps {
+        if let Some(stats) = p.and_then(|p| peers.get_mut(&p)) {
+            stats.last_tap = stats.last_tap.max(Some(*last));
+        }
+    }
+
+    let (current_streak, longest_streak) =
+        streaks(seal_days, now.with_timezone(&Local).date_naive());
+    let mut peers: Vec<PeerStats> = peers.into_values().collect();
+    peers.sort_by(|a, b| b.counts.taps.cmp(&a.counts.taps).then(a.peer.cmp(&b.peer)));
+    Ok(Insights {
+        first_tap,
+        days_since_first_tap: first_tap.map(|t| (now - t).num_days()),
+        current_streak,
+        longest_streak,
+        total,
+        periods: periods
+            .into_iter()
+            .map(|(start, counts)| PeriodStats { start, counts })
+            .collect(),
+        peers,
+    })
+}
@@ -0,0 +1,536 @@
+//! 双方设备间的历史记录同步。
+//!
+//! 打卡记录和已完成的计划只会追加，信件也只会从未拆封变为已拆封，
+//! 所以这里把它们都当作只增集合（G-Set）合并：双方各自发送全部历史，收到后取并集。
+//! 并集与合并顺序无关，双方都发送一次快照后两边的时间轴即一致。
+//!
+//! 计划可以被双方修改和删除，所以每个计划带有版本向量，收到对方的计划后按因果关系合并，
+//! 并发修改时按确定的规则选出一方保留，同时把冲突报告给前端。
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_blep::mobile::{Plan, PlanRevision, Plans};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        DeviceId, Error, FinishedPlan, FinishedPlanList, Instance, MailCover, MailCoverList,
+        MailInner, PlanDrafts, SealedInstances,
+    },
+    utils::{load_or_default, store_value},
+};
+
+/// 收件箱在 store 中的键。
+pub const INBOX_MAIL_LIST: &str = "mail-cover-list";
+
+/// 寄出信件在 store 中的键，内容由对方收件箱同步而来。
+pub const SENT_MAIL_LIST: &str = "sent-mail-list";
+
+/// 计划草稿在 store 中的键。
+pub const PLAN_DRAFTS: &str = "plan-drafts";
+
+/// 计划修订信息在 store 中的键，包括已删除计划的墓碑。
+pub const PLAN_REVISIONS: &str = "plan-revisions";
+
+/// 一封信件的完整内容。
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SyncedMail {
+    pub cover: MailCover,
+    pub inner: MailInner,
+}
+
+/// 一台设备的全部历史。
+#[derive(Serialize, Deserialize, Default)]
+pub struct HistorySnapshot {
+    /// 发送方的设备 uuid，旧版本没有
+    #[serde(default)]
+    pub device: Option<Uuid>,
+    pub instances: Vec<Instance>,
+    pub finished: Vec<FinishedPlan>,
+    /// 发送方收到的信件，即接收方寄出的信件。
+    pub inbox: HashMap<Uuid, SyncedMail>,
+    /// 发送方寄出的信件，即接收方收到的信件。
+    pub sent: HashMap<Uuid, SyncedMail>,
+}
+
+/// 一次合并新增的记录数量，发给前端用于提示。
+#[derive(Serialize, Clone, Default)]
+pub struct HistorySyncSummary {
+    pub instances: usize,
+    pub finished: usize,
+    pub mails: usize,
+}
+
+fn load_mails(app: &AppHandle, key: &str) -> Result<HashMap<Uuid, SyncedMail>, Error> {
+    let covers: MailCoverList = load_or_default(app, key)?;
+    covers
+        .mails
+        .into_iter()
+        .map(|(uuid, cover)| {
+            let inner: MailInner = load_or_default(app, &format!("mail-{}", uuid))?;
+            Ok((uuid, SyncedMail { cover, inner }))
+        })
+        .collect()
+}
+
+/// 从 store 中收集本机的全部历史。
+pub fn snapshot(app: &AppHandle) -> Result<HistorySnapshot, Error> {
+    let instances: SealedInstances = load_or_default(app, "sealed-instances")?;
+    let finished: FinishedPlanList = load_or_default(app, "finished-plan-list")?;
+    Ok(HistorySnapshot {
+        device: Some(app.state::<DeviceId>().0),
+        instances: instances.instances,
+        finished: finished.list,
+        inbox: load_mails(app, INBOX_MAIL_LIST)?,
+        sent: load_mails(app, SENT_MAIL_LIST)?,
+    })
+}
+
+/// 把对方的信件合并进本机 `key` 对应的信件列表，返回新增数量。
+///
+/// 拆封只会发生一次，所以已有的信件只需合并拆封状态。
+/// 无论收发，信件的另一方都是发来快照的设备 `peer`。
+fn merge_mails(
+    app: &AppHandle,
+    key: &str,
+    remote: HashMap<Uuid, SyncedMail>,
+    peer: Option<Uuid>,
+) -> Result<usize, Error> {
+    let mut covers: MailCoverList = load_or_default(app, key)?;
+    let mut added = 0;
+    for (uuid, mail) in remote {
+        match covers.mails.get_mut(&uuid) {
+            Some(local) => local.sealed = local.sealed && mail.cover.sealed,
+            None => {
+                store_value(app, &format!("mail-{}", uuid), &mail.inner)?;
+                let mut cover = mail.cover;
+                cover.peer = peer.or(cover.peer);
+                covers.mails.insert(uuid, cover);
+                added += 1;
+            }
+        }
+    }
+    store_value(app, key, &covers)?;
+    Ok(added)
+}
+
+/// 把对方发来的快照合并进本机 store。
+/// 把 `incoming` 中没有的记录并入 `list`，返回新增的条数。
+fn append_missing<T: PartialEq>(list: &mut Vec<T>, incoming: Vec<T>) -> usize {
+    let mut added = 0;
+    for item in incoming {
+        if !list.contains(&item) {
+            list.push(item);
+            added += 1;
+        }
+    }
+    added
+}
+
+/// 把打卡记录并入已保存的记录，只增不减，返回新增的条数。
+pub fn merge_instances(app: &AppHandle, incoming: Vec<Instance>) -> Result<usize, Error> {
+    let mut instances: SealedInstances = load_or_default(app, "sealed-instances")?;
+    let added = append_missing(&mut instances.instances, incoming);
+    instances.instances.sort_by_key(|i| i.time);
+    store_value(app, "sealed-instances", &instances)?;
+    Ok(added)
+}
+
+/// 把已完成的计划并入已保存的列表，只增不减，返回新增的条数。
+pub fn merge_finished(app: &AppHandle, incoming: Vec<FinishedPlan>) -> Result<usize, Error> {
+    let mut finished: FinishedPlanList = load_or_default(app, "finished-plan-list")?;
+    let added = append_missing(&mut finished.list, incoming);
+    finished.list.sort_by_key(|f| f.time);
+    store_value(app, "finished-plan-list", &finished)?;
+    Ok(added)
+}
+
+pub fn merge_history(app: &AppHandle, remote: &str) -> Result<HistorySyncSummary, Error> {
+    let remote: HistorySnapshot =
+        serde_json::from_str(remote).map_err(|e| Error::Load(e.to_string()))?;
+    let mut summary = HistorySyncSummary::default();
+
+    summary.instances = merge_instances(app, remote.instances)?;
+    summary.finished = merge_finished(app, remote.finished)?;
+
+    summary.mails += merge_mails(app, SENT_MAIL_LIST, remote.inbox, remote.device)?;
+    summary.mails += merge_mails(app, INBOX_MAIL_LIST, remote.sent, remote.device)?;
+    log::info!(
+        "History merged: {} instances, {} finished plans, {} mails",
+        summary.instances,
+        summary.finished,
+        summary.mails
+    );
+    Ok(summary)
+}
+
+/// 双方并发修改了同一个计划。
+#[derive(Serialize, Clone)]
+pub struct PlanConflict {
+    pub id: Uuid,
+    /// 本机的版本，`None` 表示已删除
+    pub local: Option<Plan>,
+    /// 对方的版本，`None` 表示已删除
+    pub remote: Option<Plan>,
+    /// 是否保留了对方的版本
+    pub kept_remote: bool,
+}
+
+/// 合并对方计划的结果。
+pub struct PlanMergeResult {
+    pub plans: Plans,
+    pub conflicts: Vec<PlanConflict>,
+}
+
+/// 两个版本向量的因果关系。
+enum Causality {
+    Before,
+    After,
+    Equal,
+    Concurrent,
+}
+
+fn compare(a: &HashMap<Uuid, u64>, b: &HashMap<Uuid, u64>) -> Causality {
+    let (mut less, mut greater) = (false, false);
+    for key in a.keys().chain(b.keys()) {
+        let x = a.get(key).copied().unwrap_or(0);
+        let y = b.get(key).copied().unwrap_or(0);
+        less |= x < y;
+        greater |= x > y;
+    }
+    match (less, greater) {
+        (false, false) => Causality::Equal,
+        (true, false) => Causality::Before,
+        (false, true) => Causality::After,
+        (true, true) => Causality::Concurrent,
+    }
+}
+
+fn merge_version(a: &HashMap<Uuid, u64>, b: &HashMap<Uuid, u64>) -> HashMap<Uuid, u64> {
+    let mut merged = a.clone();
+    for (key, &count) in b {
+        let entry = merged.entry(*key).or_default();
+        *entry = (*entry).max(count);
+    }
+    merged
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// 把前端写入的计划草稿和上一次保存的比较，为改动和删除的计划记录新的修订。
+pub fn stamp_plans(
+    app: &AppHandle,
+    device: Uuid,
+    drafts: &HashMap<Uuid, Plan>,
+) -> Result<HashMap<Uuid, PlanRevision>, Error> {
+    let old: PlanDrafts = load_or_default(app, PLAN_DRAFTS)?;
+    let mut revisions: HashMap<Uuid, PlanRevision> = load_or_default(app, PLAN_REVISIONS)?;
+    let now = now_millis();
+    let mut bump = |id: Uuid, deleted: bool| {
+        let rev = revisions.entry(id).or_default();
+        *rev.version.entry(device).or_default() += 1;
+        rev.author = device;
+        rev.modified = now;
+        rev.deleted = deleted;
+    };
+    for (id, plan) in drafts {
+        if old.drafts.get(id) != Some(plan) {
+            bump(*id, false);
+        }
+    }
+    for id in old.drafts.keys() {
+        if !drafts.contains_key(id) {
+            bump(*id, true);
+        }
+    }
+    store_value(app, PLAN_REVISIONS, &revisions)?;
+    Ok(revisions)
+}
+
+/// 并发修改时决定保留哪一方：修改时间晚的优先，其次比较作者和内容，
+/// 双方用同样的规则计算，所以一定选出同一个版本，连同相同的修订信息。
+fn remote_wins(
+    local: (&PlanRevision, Option<&Plan>),
+    remote: (&PlanRevision, Option<&Plan>),
+) -> bool {
+    let key = |(rev, plan): (&PlanRevision, Option<&Plan>)| {
+        (
+            rev.modified,
+            rev.author,
+            serde_json::to_string(&plan).unwrap(),
+        )
+    };
+    key(remote) > key(local)
+}
+
+/// 比较两个计划的内容。确认完成的设备会单独取并集，不算作冲突。
+fn same_content(a: Option<&Plan>, b: Option<&Plan>) -> bool {
+    let strip = |plan: Option<&Plan>| {
+        plan.map(|p| Plan {
+            confirmed_by: Vec::new(),
+            ..p.clone()
+        })
+    };
+    strip(a) == strip(b)
+}
+
+/// 按修订信息把对方的计划合并进 `drafts` 和 `revisions`，返回并发修改产生的冲突。
+///
+/// 同一次周期内双方的确认会取并集，所以两边分别确认后碰一碰即可凑齐双方的确认。
+fn merge_into(
+    drafts: &mut HashMap<Uuid, Plan>,
+    revisions: &mut HashMap<Uuid, PlanRevision>,
+    remote: &Plans,
+) -> Vec<PlanConflict> {
+    let mut conflicts = Vec::new();
+
+    let ids: HashSet<Uuid> = remote
+        .plans
+        .keys()
+        .chain(remote.revisions.keys())
+        .copied()
+        .collect();
+    for id in ids {
+        let remote_rev = remote.revisions.get(&id).cloned().unwrap_or_default();
+        let remote_plan = remote.plans.get(&id).filter(|_| !remote_rev.deleted);
+        let local_rev = revisions.get(&id).cloned();
+        let local_plan = drafts.get(&id).cloned();
+
+        let take_remote = match local_rev.as_ref() {
+            // 本机从未见过这个计划
+            None if local_plan.is_none() => true,
+            _ => {
+                let local_rev = local_rev.clone().unwrap_or_default();
+                match compare(&local_rev.version, &remote_rev.version) {
+                    Causality::Before => true,
+                    Causality::After => false,
+                    Causality::Equal | Causality::Concurrent => {
+                        let kept_remote = remote_wins(
+                            (&local_rev, local_plan.as_ref()),
+                            (&remote_rev, remote_plan),
+                        );
+                        if !same_content(local_plan.as_ref(), remote_plan) {
+                            conflicts.push(PlanConflict {
+                                id,
+                                local: local_plan.clone(),
+                                remote: remote_plan.cloned(),
+                                kept_remote,
+                            });
+                        }
+                        kept_remote
+                    }
+                }
+            }
+        };
+
+        let mut merged = if take_remote {
+            remote_rev.clone()
+        } else {
+            local_rev.clone().unwrap_or_default()
+        };
+        merged.version = merge_version(
+            &local_rev.map(|r| r.version).unwrap_or_default(),
+            &remote_rev.version,
+        );
+        if take_remote {
+            match remote_plan {
+                Some(plan) => drafts.insert(id, plan.clone()),
+                None => drafts.remove(&id),
+            };
+        }
+        if let (Some(local), Some(remote), Some(plan)) =
+            (&local_plan, remote_plan, drafts.get_mut(&id))
+        {
+            if local.due == remote.due {
+                let confirmed: BTreeSet<Uuid> = local
+                    .confirmed_by
+                    .iter()
+                    .chain(&remote.confirmed_by)
+                    .copied()
+                    .collect();
+                plan.confirmed_by = confirmed.into_iter().collect();
+            }
+        }
+        revisions.insert(id, merged);
+    }
+
+    conflicts
+}
+
+/// 把对方的计划合并进本机，写入 store 并返回合并后的全部计划。
+pub fn merge_plans(app: &AppHandle, remote: &Plans) -> Result<PlanMergeResult, Error> {
+    let mut drafts: PlanDrafts = load_or_default(app, PLAN_DRAFTS)?;
+    let mut revisions: HashMap<Uuid, PlanRevision> = load_or_default(app, PLAN_REVISIONS)?;
+    let conflicts = merge_into(&mut drafts.drafts, &mut revisions, remote);
+
+    store_value(app, PLAN_DRAFTS, &drafts)?;
+    store_value(app, PLAN_REVISIONS, &revisions)?;
+    if !conflicts.is_empty() {
+        log::warn!("{} plan conflicts while merging", conflicts.len());
+    }
+    Ok(PlanMergeResult {
+        plans: Plans {
+            selected_plan: remote.selected_plan,
+            plans: drafts.drafts,
+            revisions,
+        },
+        conflicts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(title: &str) -> Plan {
+        Plan {
+            title: title.to_string(),
+            body: String::new(),
+            due: None,
+            recurrence: None,
+            checklist: Vec::new(),
+            confirmed_by: Vec::new(),
+        }
+    }
+
+    fn rev(author: Uuid, modified: u64, version: &[(Uuid, u64)]) -> PlanRevision {
+        PlanRevision {
+            author,
+            modified,
+            version: version.iter().copied().collect(),
+            deleted: false,
+        }
+    }
+
+    fn remote(id: Uuid, plan: Option<Plan>, rev: PlanRevision) -> Plans {
+        Plans {
+            selected_plan: None,
+            plans: plan.into_iter().map(|p| (id, p)).collect(),
+            revisions: HashMap::from([(id, rev)]),
+        }
+    }
+
+    #[test]
+    fn later_modification_wins() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let (pa, pb) = (plan("a"), plan("b"));
+        let (ra, rb) = (rev(a, 1, &[(a, 1)]), rev(b, 2, &[(b, 1)]));
+        assert!(remote_wins((&ra, Some(&pa)), (&rb, Some(&pb))));
+        assert!(!remote_wins((&rb, Some(&pb)), (&ra, Some(&pa))));
+    }
+
+    #[test]
+    fn tie_is_decided_the_same_way_on_both_sides() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let (pa, pb) = (plan("a"), plan("b"));
+        let (ra, rb) = (rev(a, 1, &[(a, 1)]), rev(b, 1, &[(b, 1)]));
+        assert_ne!(
+            remote_wins((&ra, Some(&pa)), (&rb, Some(&pb))),
+            remote_wins((&rb, Some(&pb)), (&ra, Some(&pa)))
+        );
+    }
+
+    #[test]
+    fn newer_remote_replaces_local_without_conflict() {
+        let (a, b, id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut drafts = HashMap::from([(id, plan("old"))]);
+        let mut revisions = HashMap::from([(id, rev(a, 1, &[(a, 1)]))]);
+        let incoming = remote(id, Some(plan("new")), rev(b, 2, &[(a, 1), (b, 1)]));
+
+        let conflicts = merge_into(&mut drafts, &mut revisions, &incoming);
+        assert!(conflicts.is_empty());
+        assert_eq!(drafts[&id].title, "new");
+        assert_eq!(revisions[&id].version, HashMap::from([(a, 1), (b, 1)]));
+    }
+
+    #[test]
+    fn older_remote_is_ignored() {
+        let (a, id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut drafts = HashMap::from([(id, plan("new"))]);
+        let mut revisions = HashMap::from([(id, rev(a, 2, &[(a, 2)]))]);
+        let incoming = remote(id, Some(plan("old")), rev(a, 1, &[(a, 1)]));
+
+        assert!(merge_into(&mut drafts, &mut revisions, &incoming).is_empty());
+        assert_eq!(drafts[&id].title, "new");
+    }
+
+    #[test]
+    fn concurrent_edits_converge_and_report_conflict() {
+        let (a, b, id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (ra, rb) = (rev(a, 5, &[(a, 1)]), rev(b, 7, &[(b, 1)]));
+        let mut drafts_a = HashMap::from([(id, plan("from a"))]);
+        let mut revisions_a = HashMap::from([(id, ra.clone())]);
+        let mut drafts_b = HashMap::from([(id, plan("from b"))]);
+        let mut revisions_b = HashMap::from([(id, rb.clone())]);
+
+        let on_a = merge_into(
+            &mut drafts_a,
+            &mut revisions_a,
+            &remote(id, Some(plan("from b")), rb),
+        );
+        let on_b = merge_into(
+            &mut drafts_b,
+            &mut revisions_b,
+            &remote(id, Some(plan("from a")), ra),
+        );
+        assert_eq!(on_a.len(), 1);
+        assert_eq!(on_b.len(), 1);
+        assert!(on_a[0].kept_remote);
+        assert!(!on_b[0].kept_remote);
+        assert_eq!(drafts_a, drafts_b);
+        assert_eq!(revisions_a, revisions_b);
+    }
+
+    #[test]
+    fn newer_tombstone_deletes_local_plan() {
+        let (a, b, id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut drafts = HashMap::from([(id, plan("doomed"))]);
+        let mut revisions = HashMap::from([(id, rev(a, 1, &[(a, 1)]))]);
+        let mut tombstone = rev(b, 2, &[(a, 1), (b, 1)]);
+        tombstone.deleted = true;
+
+        merge_into(&mut drafts, &mut revisions, &remote(id, None, tombstone));
+        assert!(!drafts.contains_key(&id));
+        assert!(revisions[&id].deleted);
+    }
+
+    #[test]
+    fn confirmations_are_united() {
+        let (a, b, id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut local = plan("p");
+        local.confirmed_by = vec![a];
+        let mut theirs = plan("p");
+        theirs.confirmed_by = vec![b];
+        let mut drafts = HashMap::from([(id, local)]);
+        let mut revisions = HashMap::from([(id, rev(a, 1, &[(a, 1)]))]);
+
+        let conflicts = merge_into(
+            &mut drafts,
+            &mut revisions,
+            &remote(id, Some(theirs), rev(b, 1, &[(b, 1)])),
+        );
+        assert!(conflicts.is_empty());
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(drafts[&id].confirmed_by, expected);
+    }
+
+    #[test]
+    fn append_missing_keeps_existing_records() {
+        let mut list = vec![1, 2];
+        assert_eq!(append_missing(&mut list, vec![2, 3, 3]), 1);
+        assert_eq!(list, vec![1, 2, 3]);
+        assert_eq!(append_missing(&mut list, Vec::new()), 0);
+        assert_eq!(list, vec![1, 2, 3]);
+    }
+}
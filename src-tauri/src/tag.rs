@@ -0,0 +1,251 @@
+//! NFC 标签留言：把一条消息加密后写进 NDEF 贴纸，对方之后用应用读取。
+//!
+//! 写入的是设置好、等待触碰发送的那条消息，只是改为写进标签。
+//! 读到的消息和 BLE 收到的一样交给 `ble::dispatch` 处理。
+//!
+//! 记录格式：`版本(1) | 发送方 uuid(16) | nonce(12) | 密文`，明文是 CBOR 编码的消息。
+//! 密钥由双方身份密钥的共享密钥和双方 uuid 推得，只有写入时指定的对方能解开；头部作为附加数据参与认证。
+//!
+//! 标签可以被反复读取，所以一次性消息不能写进标签，读到的也会拒绝。
+//! 打卡提议要双方当面确认，同样不能留在标签里。
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tauri::{async_runtime, command, AppHandle, Manager};
+use tauri_plugin_blep::mobile::Message;
+use tauri_plugin_nfc2::Nfc2Ext;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    ble::{self, DeviceBridge},
+    identity::Identity,
+    models::{DeviceId, Error, MessageType},
+    tap,
+};
+
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 1 + 16 + 12;
+const TAG_LEN: usize = 16;
+/// 记录的最大长度。按常见的 NTAG216 的 888 字节，减去 NDEF 头部、外部类型名和应用记录约 61 字节，再留一些余量。
+pub const MAX_RECORD_LEN: usize = 800;
+
+/// 能写进标签的消息：不能是控制消息和空消息，也不能是只能查看一次的消息或打卡提议。
+fn check_writable(msg: &Message) -> Result<(), Error> {
+    match msg {
+        Message::Disposable(_) => Err(Error::DisposableOnTag),
+        Message::Seal(_) => Err(Error::SealOnTag),
+        // 标签只能留下主消息，控制消息只在连接中有意义
+        m if m.is_control() || matches!(m, Message::Empty) => {
+            Err(Error::InvalidTag("message".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 一对设备共用的标签密钥，与谁写谁读无关。
+fn pair_key(app: &AppHandle, peer: Uuid) -> Result<Key, Error> {
+    let peer_key = tap::peer_key(app, peer)?.ok_or(Error::UnknownPeer(peer))?;
+    let identity = app.state::<Identity>();
+    let shared = identity.shared_secret(&peer_key)?;
+    let (a, b) = if identity.device < peer {
+        (identity.device, peer)
+    } else {
+        (peer, identity.device)
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(b"whispact-tag");
+    hasher.update(shared);
+    hasher.update(a.as_bytes());
+    hasher.update(b.as_bytes());
+    Ok(Key::clone_from_slice(&hasher.finalize()))
+}
+
+/// 用 `key` 把 `sender` 写的消息加密成标签记录。
+fn seal(key: &Key, sender: Uuid, msg: &Message) -> Result<Vec<u8>, Error> {
+    check_writable(msg)?;
+    let mut plaintext = Vec::new();
+    ciborium::into_writer(msg, &mut plaintext).map_err(|e| Error::InvalidTag(e.to_string()))?;
+    let len = HEADER_LEN + plaintext.len() + TAG_LEN;
+    if len > MAX_RECORD_LEN {
+        return Err(Error::TagTooLarge(len));
+    }
+
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let mut record = Vec::with_capacity(len);
+    record.push(VERSION);
+    record.extend_from_slice(sender.as_bytes());
+    record.extend_from_slice(&nonce);
+
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad: &record,
+            },
+        )
+        .map_err(|_| Error::InvalidTag("encrypt".to_string()))?;
+    record.extend_from_slice(&ciphertext);
+    Ok(record)
+}
+
+/// 记录的写入方，格式不对时返回错误。
+fn sender_of(record: &[u8]) -> Result<Uuid, Error> {
+    if record.len() <= HEADER_LEN || record[0] != VERSION {
+        return Err(Error::InvalidTag("format".to_string()));
+    }
+    Ok(Uuid::from_slice(&record[1..17]).unwrap())
+}
+
+/// 用 `key` 解开标签记录。
+fn open(key: &Key, record: &[u8]) -> Result<Message, Error> {
+    sender_of(record)?;
+    let (header, ciphertext) = record.split_at(HEADER_LEN);
+    let plaintext = ChaCha20Poly1305::new(key)
+        .decrypt(
+            Nonce::from_slice(&header[17..]),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| Error::TagNotForUs)?;
+    let msg: Message = ciborium::from_reader(plaintext.as_slice())
+        .map_err(|e| Error::InvalidTag(e.to_string()))?;
+    check_writable(&msg)?;
+    Ok(msg)
+}
+
+/// 把消息加密成写给 `peer` 的标签记录，超过 `MAX_RECORD_LEN` 时返回错误。
+pub fn encode(app: &AppHandle, peer: Uuid, msg: &Message) -> Result<Vec<u8>, Error> {
+    seal(&pair_key(app, peer)?, app.state::<DeviceId>().0, msg)
+}
+
+/// 解开标签记录，返回写入方和消息。
+pub fn decode(app: &AppHandle, record: &[u8]) -> Result<(Uuid, Message), Error> {
+    let sender = sender_of(record)?;
+    Ok((sender, open(&pair_key(app, sender)?, record)?))
+}
+
+/// 读到标签，解开后按收到消息处理。
+pub fn receive(app: &AppHandle, record: &[u8]) -> Result<(), Error> {
+    let (sender, msg) = decode(app, record)?;
+    log::info!("Tag from {sender}: {:?}", MessageType::from(&msg));
+    ble::dispatch(app, app.state::<DeviceId>().0, Some(sender), &msg);
+    Ok(())
+}
+
+/// 把等待发送的消息写进下一次触碰的标签。
+///
+/// 不指定对方时，只触碰过一台设备才能确定写给谁。消息在等待触碰前就编码好，
+/// 一次性消息和太长的消息会立即报错。写入失败时消息放回，仍可以触碰发送。
+#[command]
+pub async fn write_tag(app: AppHandle, peer: Option<Uuid>) -> Result<(), Error> {
+    let peer = match peer {
+        Some(peer) => peer,
+        None => match tap::known_peers(&app)?.as_slice() {
+            [only] => *only,
+            _ => return Err(Error::NoTagPeer),
+        },
+    };
+    let msg = {
+        let state = app.state::<Mutex<DeviceBridge>>();
+        let mut guard = state.lock().await;
+        (*guard).take_msg().ok_or(Error::NothingToWrite)?
+    };
+    let result = async {
+        let record = encode(&app, peer, &msg)?;
+        let handle = app.clone();
+        // 一直阻塞到触碰标签
+        async_runtime::spawn_blocking(move || handle.nfc2().write_tag(&record))
+            .await
+            .map_err(|e| Error::TagWrite(e.to_string()))?
+            .map_err(|e| Error::TagWrite(e.to_string()))
+    }
+    .await;
+    if result.is_err() {
+        let state = app.state::<Mutex<DeviceBridge>>();
+        let mut guard = state.lock().await;
+        (*guard).set_msg(msg)?;
+    } else {
+        log::info!("Message written to tag for {peer}");
+    }
+    result
+}
+
+#[command]
+pub fn cancel_write_tag(app: AppHandle) -> Result<(), Error> {
+    app.nfc2()
+        .cancel_write_tag()
+        .map_err(|e| Error::TagWrite(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        Key::clone_from_slice(&[byte; 32])
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let sender = Uuid::new_v4();
+        let msg = Message::Mail("写在贴纸里的信".to_string());
+        let record = seal(&key(1), sender, &msg).unwrap();
+        assert!(record.len() <= MAX_RECORD_LEN);
+        assert_eq!(sender_of(&record).unwrap(), sender);
+        assert_eq!(open(&key(1), &record).unwrap().to_string(), msg.to_string());
+    }
+
+    #[test]
+    fn record_for_another_pair_is_rejected() {
+        let record = seal(&key(1), Uuid::new_v4(), &Message::Mail("hi".to_string())).unwrap();
+        assert!(matches!(open(&key(2), &record), Err(Error::TagNotForUs)));
+    }
+
+    #[test]
+    fn tampered_header_is_rejected() {
+        let mut record = seal(&key(1), Uuid::new_v4(), &Message::Mail("hi".to_string())).unwrap();
+        record[1] ^= 1;
+        assert!(matches!(open(&key(1), &record), Err(Error::TagNotForUs)));
+        record[0] = VERSION + 1;
+        assert!(matches!(open(&key(1), &record), Err(Error::InvalidTag(_))));
+    }
+
+    #[test]
+    fn disposable_seal_and_control_messages_are_refused() {
+        let sender = Uuid::new_v4();
+        assert!(matches!(
+            seal(&key(1), sender, &Message::Disposable("once".to_string())),
+            Err(Error::DisposableOnTag)
+        ));
+        assert!(matches!(
+            seal(&key(1), sender, &Message::Seal("{}".to_string())),
+            Err(Error::SealOnTag)
+        ));
+        assert!(matches!(
+            seal(&key(1), sender, &Message::Subscribed([0; 8])),
+            Err(Error::InvalidTag(_))
+        ));
+        assert!(matches!(
+            seal(&key(1), sender, &Message::Empty),
+            Err(Error::InvalidTag(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_message_is_refused() {
+        let msg = Message::Mail("x".repeat(MAX_RECORD_LEN));
+        assert!(matches!(
+            seal(&key(1), Uuid::new_v4(), &msg),
+            Err(Error::TagTooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+//! 触碰令牌：NFC 交换的不再是固定的 uuid，而是带随机数和时间戳的签名令牌。
+//!
+//! 令牌每隔一段时间重新生成并交给 NFC 插件，读卡和被读时都发出最新的令牌。
+//! 收到对方的令牌后先验证签名、时效和随机数，通过后才会建立 BLE 连接。
+//! 对方的公钥第一次见到时记下，之后同一 uuid 必须使用同一公钥。
+//!
+//! 令牌里的会话提示把随后的 BLE 会话绑定到这次触碰：主端订阅后要出示从端令牌里的提示，
+//! 从端核对是自己刚发出的才开始收发，附近其他设备即使连上从端的服务也拿不到消息。
+//!
+//! 令牌格式（定长 144 字节）：
+//! `uuid(16) | nonce(16) | 毫秒时间戳(8, 大端) | 会话提示(8) | 公钥(32) | 签名(64)`，
+//! 签名覆盖前 80 字节。
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use tauri::{async_runtime, AppHandle, Emitter, Manager};
+use tauri_plugin_nfc2::Nfc2Ext;
+use uuid::Uuid;
+
+use crate::{
+    clock::TrustedClock,
+    identity::{self, Identity},
+    models::Error,
+    utils::{load_or_default, store_value},
+};
+
+const PEER_KEYS: &str = "peer-keys";
+
+const SIGNED_LEN: usize = 80;
+const TOKEN_LEN: usize = SIGNED_LEN + 64;
+
+/// 令牌的有效期，也是允许的双方时钟误差。
+const TOKEN_TTL: chrono::Duration = chrono::Duration::seconds(60);
+/// 令牌的刷新间隔，小于有效期以免对方读到刚过期的令牌。
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 验证通过的对方令牌。
+pub struct TapToken {
+    pub device: Uuid,
+    pub nonce: [u8; 16],
+    pub issued_at: DateTime<Utc>,
+    /// 随机的会话提示，主端连接后出示给从端
+    pub session_hint: [u8; 8],
+    pub public_key: [u8; 32],
+}
+
+impl TapToken {
+    fn parse(bytes: &[u8]) -> Result<(Self, &[u8], &[u8]), Error> {
+        if bytes.len() != TOKEN_LEN {
+            return Err(Error::InvalidTapToken(format!("length {}", bytes.len())));
+        }
+        let (signed, signature) = bytes.split_at(SIGNED_LEN);
+        let millis = i64::from_be_bytes(signed[32..40].try_into().unwrap());
+        let token = Self {
+            device: Uuid::from_slice(&signed[..16]).unwrap(),
+            nonce: signed[16..32].try_into().unwrap(),
+            issued_at: DateTime::from_timestamp_millis(millis)
+                .ok_or(Error::InvalidTapToken("timestamp".to_string()))?,
+            session_hint: signed[40..48].try_into().unwrap(),
+            public_key: signed[48..80].try_into().unwrap(),
+        };
+        Ok((token, signed, signature))
+    }
+}
+
+/// 见过的随机数，过期后清理。
+#[derive(Default)]
+pub struct SeenNonces(Mutex<HashMap<[u8; 16], DateTime<Utc>>>);
+
+impl SeenNonces {
+    /// 记下随机数，见过时返回 `false`。顺带清理早已过期的随机数。
+    fn insert(&self, nonce: [u8; 16], issued_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        let mut seen = self.0.lock().unwrap();
+        seen.retain(|_, at| now - *at <= TOKEN_TTL * 2);
+        seen.insert(nonce, issued_at).is_none()
+    }
+}
+
+/// 本机令牌里发出的会话提示，在令牌有效期内等待对方出示。
+#[derive(Default)]
+pub struct SessionHints(Mutex<HashMap<[u8; 8], DateTime<Utc>>>);
+
+impl SessionHints {
+    /// 生成并记下一个新的会话提示。
+    fn issue(&self, now: DateTime<Utc>) -> [u8; 8] {
+        let mut hint = [0u8; 8];
+        OsRng.fill_bytes(&mut hint);
+        let mut hints = self.0.lock().unwrap();
+        hints.retain(|_, at| now - *at <= TOKEN_TTL);
+        hints.insert(hint, now);
+        hint
+    }
+
+    /// 对方出示的提示是否由本机在有效期内发出。每个提示只能用一次。
+    pub fn redeem(&self, hint: [u8; 8], now: DateTime<Utc>) -> bool {
+        let mut hints = self.0.lock().unwrap();
+        hints.retain(|_, at| now - *at <= TOKEN_TTL);
+        hints.remove(&hint).is_some()
+    }
+}
+
+/// 生成本机的新令牌。
+pub fn issue(app: &AppHandle) -> Vec<u8> {
+    let now = app.state::<TrustedClock>().now();
+    let hint = app.state::<SessionHints>().issue(now);
+    sign(&app.state::<Identity>(), now, hint)
+}
+
+/// 用 `identity` 签出一个 `now` 时刻、带会话提示 `hint` 的令牌。
+fn sign(identity: &Identity, now: DateTime<Utc>, hint: [u8; 8]) -> Vec<u8> {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut token = Vec::with_capacity(TOKEN_LEN);
+    token.extend_from_slice(identity.device.as_bytes());
+    token.extend_from_slice(&nonce);
+    token.extend_from_slice(&now.timestamp_millis().to_be_bytes());
+    token.extend_from_slice(&hint);
+    token.extend_from_slice(&identity.verifying_key());
+    let signature = identity.sign_bytes(&token);
+    token.extend_from_slice(&signature);
+    token
+}
+
+/// 换一个新令牌交给 NFC 插件。每次触碰双方都读到了对方的令牌，所以触碰后也要换。
+pub fn rotate(app: &AppHandle) {
+    app.nfc2().set_token(&issue(app)).unwrap_or_else(|e| {
+        app.emit("err", Error::InitNfc(e.to_string())).unwrap();
+    });
+}
+
+/// 定期刷新令牌，保证对方读到的总在有效期内。
+pub fn refresh(app: AppHandle) {
+    async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+            rotate(&app);
+        }
+    });
+}
+
+/// 检查令牌的签名、时效和随机数，不涉及公钥是否与之前见过的一致。
+fn check(bytes: &[u8], now: DateTime<Utc>, seen: &SeenNonces) -> Result<TapToken, Error> {
+    let (token, signed, signature) = TapToken::parse(bytes)?;
+    let public_key = STANDARD.encode(token.public_key);
+    identity::verify(&public_key, signed, &STANDARD.encode(signature))?;
+
+    if (now - token.issued_at).abs() > TOKEN_TTL {
+        return Err(Error::TapTokenExpired);
+    }
+    if !seen.insert(token.nonce, token.issued_at, now) {
+        return Err(Error::TapTokenReplayed);
+    }
+    Ok(token)
+}
+
+/// 验证对方的令牌，返回其中的信息。令牌时间与本机相符，顺带证实本机时钟。
+pub fn verify(app: &AppHandle, bytes: &[u8]) -> Result<TapToken, Error> {
+    let clock = app.state::<TrustedClock>();
+    let token = check(bytes, clock.now(), &app.state::<SeenNonces>())?;
+    let public_key = STANDARD.encode(token.public_key);
+
+    let mut keys: HashMap<Uuid, String> = load_or_default(app, PEER_KEYS)?;
+    match keys.get(&token.device) {
+        Some(known) if *known != public_key => return Err(Error::PeerKeyMismatch(token.device)),
+        Some(_) => {}
+        None => {
+            log::info!("Pinned public key of {}", token.device);
+            keys.insert(token.device, public_key);
+            store_value(app, PEER_KEYS, &keys)?;
+        }
+    }
+    clock.confirm(app)?;
+    Ok(token)
+}
+
+/// 触碰过的设备的公钥。
+pub fn peer_key(app: &AppHandle, device: Uuid) -> Result<Option<[u8; 32]>, Error> {
+    let keys: HashMap<Uuid, String> = load_or_default(app, PEER_KEYS)?;
+    Ok(keys
+        .get(&device)
+        .and_then(|k| STANDARD.decode(k).ok())
+        .and_then(|k| k.try_into().ok()))
+}
+
+/// 触碰过的全部设备。
+pub fn known_peers(app: &AppHandle) -> Result<Vec<Uuid>, Error> {
+    let keys: HashMap<Uuid, String> = load_or_default(app, PEER_KEYS)?;
+    Ok(keys.into_keys().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_token_verifies() {
+        let identity = Identity::ephemeral();
+        let now = Utc::now();
+        let bytes = sign(&identity, now, [7; 8]);
+        assert_eq!(bytes.len(), TOKEN_LEN);
+
+        let token = check(&bytes, now, &SeenNonces::default()).unwrap();
+        assert_eq!(token.device, identity.device);
+        assert_eq!(token.session_hint, [7; 8]);
+        assert_eq!(token.public_key, identity.verifying_key());
+        assert_eq!(token.issued_at.timestamp_millis(), now.timestamp_millis());
+    }
+
+    #[test]
+    fn replayed_token_is_rejected() {
+        let now = Utc::now();
+        let bytes = sign(&Identity::ephemeral(), now, [7; 8]);
+        let seen = SeenNonces::default();
+        assert!(check(&bytes, now, &seen).is_ok());
+        assert!(matches!(
+            check(&bytes, now, &seen),
+            Err(Error::TapTokenReplayed)
+        ));
+    }
+
+    #[test]
+    fn stale_token_is_rejected() {
+        let now = Utc::now();
+        let bytes = sign(&Identity::ephemeral(), now - TOKEN_TTL * 2, [7; 8]);
+        assert!(matches!(
+            check(&bytes, now, &SeenNonces::default()),
+            Err(Error::TapTokenExpired)
+        ));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let now = Utc::now();
+        let mut bytes = sign(&Identity::ephemeral(), now, [7; 8]);
+        bytes[0] ^= 1;
+        assert!(matches!(
+            check(&bytes, now, &SeenNonces::default()),
+            Err(Error::BadSignature)
+        ));
+        assert!(matches!(
+            check(&bytes[1..], now, &SeenNonces::default()),
+            Err(Error::InvalidTapToken(_))
+        ));
+    }
+
+    #[test]
+    fn session_hint_is_redeemed_once_within_ttl() {
+        let hints = SessionHints::default();
+        let now = Utc::now();
+        let hint = hints.issue(now);
+        assert!(!hints.redeem([0; 8], now));
+        assert!(hints.redeem(hint, now));
+        assert!(!hints.redeem(hint, now));
+
+        let stale = hints.issue(now - TOKEN_TTL * 2);
+        assert!(!hints.redeem(stale, now));
+    }
+}
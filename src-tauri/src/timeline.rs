@@ -0,0 +1,149 @@
+//! 时间轴：把打卡、已完成的计划和收发的信件合成一条按时间排序的事件流。
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{command, AppHandle, Manager};
+use uuid::Uuid;
+
+use crate::{
+    models::{DeviceId, Error, FinishedPlanList, MailCoverList, SealedInstances},
+    sync,
+    utils::{legacy_id, load_or_default},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TimelineKind {
+    Seal,
+    FinishedPlan,
+    Mail,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+    /// 双方共同完成，如打卡和计划
+    Shared,
+}
+
+#[derive(Serialize)]
+pub struct TimelineEvent {
+    pub kind: TimelineKind,
+    pub id: Uuid,
+    pub time: DateTime<Utc>,
+    pub direction: Direction,
+    /// 另一方的设备 uuid，旧版本记录没有
+    pub peer: Option<Uuid>,
+    /// 打卡内容、计划标题或信件封面
+    pub title: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct TimeRange {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct PageRequest {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct TimelinePage {
+    pub events: Vec<TimelineEvent>,
+    /// 下一页的 offset，没有更多时为 `None`
+    pub next: Option<usize>,
+    pub total: usize,
+}
+
+/// 双方共同的记录中，除本机外的另一方。
+fn other(devices: &[Uuid], me: Uuid) -> Option<Uuid> {
+    devices.iter().find(|d| **d != me).copied()
+}
+
+fn collect(app: &AppHandle) -> Result<Vec<TimelineEvent>, Error> {
+    let me = app.state::<DeviceId>().0;
+    let mut events = Vec::new();
+
+    let sealed: SealedInstances = load_or_default(app, "sealed-instances")?;
+    events.extend(sealed.instances.into_iter().map(|i| {
+        let id =
+            i.id.unwrap_or_else(|| legacy_id(&[&i.instance, &i.time.to_rfc3339()]));
+        TimelineEvent {
+            kind: TimelineKind::Seal,
+            id,
+            time: i.time,
+            direction: Direction::Shared,
+            peer: other(&i.devices, me),
+            title: i.instance,
+        }
+    }));
+
+    let finished: FinishedPlanList = load_or_default(app, "finished-plan-list")?;
+    events.extend(finished.list.into_iter().map(|f| {
+        let id =
+            f.id.unwrap_or_else(|| legacy_id(&[&f.plan.title, &f.time.to_rfc3339()]));
+        TimelineEvent {
+            kind: TimelineKind::FinishedPlan,
+            id,
+            time: f.time,
+            direction: Direction::Shared,
+            peer: other(&f.plan.confirmed_by, me),
+            title: f.plan.title,
+        }
+    }));
+
+    for (key, direction) in [
+        (sync::INBOX_MAIL_LIST, Direction::Received),
+        (sync::SENT_MAIL_LIST, Direction::Sent),
+    ] {
+        let covers: MailCoverList = load_or_default(app, key)?;
+        events.extend(covers.mails.into_iter().map(|(id, cover)| TimelineEvent {
+            kind: TimelineKind::Mail,
+            id,
+            time: cover.timestamp,
+            direction,
+            peer: cover.peer,
+            title: cover.cover,
+        }));
+    }
+    Ok(events)
+}
+
+/// 按时间从早到晚分页返回事件，可按时间范围、类型和另一方筛选。
+#[command]
+pub fn timeline(
+    app: AppHandle,
+    range: Option<TimeRange>,
+    kinds: Option<Vec<TimelineKind>>,
+    peer: Option<Uuid>,
+    page: Option<PageRequest>,
+) -> Result<TimelinePage, Error> {
+    let range = range.unwrap_or_default();
+    let kinds = kinds.unwrap_or_default();
+    let page = page.unwrap_or_default();
+
+    let mut events: Vec<TimelineEvent> = collect(&app)?
+        .into_iter()
+        .filter(|e| kinds.is_empty() || kinds.contains(&e.kind))
+        .filter(|e| peer.is_none() || e.peer == peer)
+        .filter(|e| range.from.is_none_or(|from| e.time >= from))
+        .filter(|e| range.to.is_none_or(|to| e.time <= to))
+        .collect();
+    events.sort_by(|a, b| a.time.cmp(&b.time).then(a.id.cmp(&b.id)));
+
+    let total = events.len();
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    let events: Vec<TimelineEvent> = events.into_iter().skip(page.offset).take(limit).collect();
+    let end = page.offset + events.len();
+    Ok(TimelinePage {
+        events,
+        next: (end < total).then_some(end),
+        total,
+    })
+}
@@ -0,0 +1,73 @@
+//! 时间戳。
+//!
+//! 记录中的时间统一为 UTC 的 `DateTime`，序列化为 RFC3339。
+//! 旧版本由前端按本地格式生成时间字符串，读取时尽量按本地时间解析，启动时迁移一次。
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use tauri::AppHandle;
+
+use crate::{
+    models::{Error, FinishedPlanList, MailCoverList, SealedInstances},
+    sync,
+    utils::{load_or_default, store_value},
+};
+
+/// 旧版本前端 `toLocaleDateString() + " " + toLocaleTimeString()` 可能生成的格式。
+const LEGACY_FORMATS: &[&str] = &[
+    "%Y/%m/%d %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%m/%d/%Y %I:%M:%S %p",
+    "%d/%m/%Y %H:%M:%S",
+];
+
+/// 解析时间字符串，先按 RFC3339，再按旧版本的本地格式。
+pub fn parse(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    LEGACY_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s.trim(), f).ok())
+        .and_then(|naive| Local.from_local_datetime(&naive).earliest())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// 宽松地反序列化时间，用于 `#[serde(deserialize_with)]`。
+///
+/// 兼容旧版本的字符串和毫秒时间戳，实在无法解析时记为当前时间。
+pub fn lenient<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    let parsed = match &value {
+        Value::String(s) => parse(s),
+        Value::Number(n) => n.as_i64().and_then(DateTime::from_timestamp_millis),
+        _ => None,
+    };
+    Ok(parsed.unwrap_or_else(|| {
+        log::warn!("Unrecognized timestamp {value}, stamped with now");
+        Utc::now()
+    }))
+}
+
+/// 把旧版本 store 中的时间字符串改写为 RFC3339，只执行一次。
+pub fn migrate(app: &AppHandle) -> Result<(), Error> {
+    if load_or_default::<bool>(app, "timestamps-migrated")? {
+        return Ok(());
+    }
+    let instances: SealedInstances = load_or_default(app, "sealed-instances")?;
+    store_value(app, "sealed-instances", &instances)?;
+    let finished: FinishedPlanList = load_or_default(app, "finished-plan-list")?;
+    store_value(app, "finished-plan-list", &finished)?;
+    for key in [
+        sync::INBOX_MAIL_LIST,
+        sync::SENT_MAIL_LIST,
+        "mail-drafts-cover-list",
+    ] {
+        let covers: MailCoverList = load_or_default(app, key)?;
+        store_value(app, key, &covers)?;
+    }
+    store_value(app, "timestamps-migrated", &true)?;
+    log::info!("Timestamps migrated to RFC3339");
+    Ok(())
+}
@@ -1,50 +1,95 @@
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
 use tauri::{command, plugin::PermissionState, AppHandle, Manager};
 use tauri_plugin_blep::{
     mobile::{Message, Plans},
     BlepExt,
 };
+use tauri_plugin_nfc2::{Nfc2Ext, SettingsTarget};
 use tauri_plugin_store::StoreExt;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use crate::{
+    attachment,
     ble::DeviceBridge,
+    mailbox,
     models::{
-        DisposableDrafts, Error, FinishedPlanList, Mail, MailCoverList, MailInner, PlanDrafts,
-        SealedInstances,
+        DeviceId, DisposableDrafts, Error, FinishedPlanList, Mail, MailCoverList, MailInner,
+        PlanDrafts, SealedInstances,
     },
+    search::SearchIndex,
+    sync,
 };
 
-#[command]
-pub async fn set_disposable_msg(app: AppHandle, msg: String) -> Result<(), Error> {
-    let state = app.state::<Mutex<DeviceBridge>>();
-    let mut guard = state.lock().await;
-    (*guard).set_msg(Message::Disposable(msg))?;
+/// 读取 `store.json` 中的一项，不存在时返回默认值。
+pub(crate) fn load_or_default<T: DeserializeOwned + Default>(
+    app: &AppHandle,
+    key: &str,
+) -> Result<T, Error> {
+    let store = app.store("store.json").map_err(Into::<Error>::into)?;
+    match store.get(key) {
+        Some(v) => serde_json::from_value(v).map_err(|e| Error::Load(e.to_string())),
+        None => Ok(T::default()),
+    }
+}
+
+/// 写入 `store.json` 中的一项，同时更新搜索索引。
+pub(crate) fn store_value<T: Serialize>(app: &AppHandle, key: &str, data: &T) -> Result<(), Error> {
+    let store = app.store("store.json").map_err(Into::<Error>::into)?;
+    let value = serde_json::to_value(data).unwrap();
+    if let Some(index) = app.try_state::<SearchIndex>() {
+        index.update(key, Some(&value));
+    }
+    store.set(key, value);
     Ok(())
 }
 
+/// 旧版本记录没有 uuid，由内容生成一个固定的。
+pub(crate) fn legacy_id(parts: &[&str]) -> Uuid {
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, parts.join("\u{0}").as_bytes())
+}
+
 #[command]
-pub async fn set_seal_msg(app: AppHandle, msg: String) -> Result<(), Error> {
+pub async fn set_plan_sync_msg(app: AppHandle, mut plan: Plans) -> Result<(), Error> {
+    let device = app.state::<DeviceId>().0;
+    plan.revisions = sync::stamp_plans(&app, device, &plan.plans)?;
+    // 修订已经记下，草稿也要一起保存，否则下次比较时同样的改动会再记一次
+    store_value(
+        &app,
+        sync::PLAN_DRAFTS,
+        &PlanDrafts {
+            drafts: plan.plans.clone(),
+        },
+    )?;
     let state = app.state::<Mutex<DeviceBridge>>();
     let mut guard = state.lock().await;
-    (*guard).set_msg(Message::Seal(msg))?;
+    (*guard).set_msg(Message::PlanSync(plan))?;
     Ok(())
 }
 
 #[command]
-pub async fn set_plan_sync_msg(app: AppHandle, plan: Plans) -> Result<(), Error> {
+pub async fn set_mail_msg(app: AppHandle, mut mail: Mail) -> Result<(), Error> {
+    mail.id.get_or_insert_with(Uuid::new_v4);
+    mail.from = Some(app.state::<DeviceId>().0);
+    mail.sent_at = Some(Utc::now());
+    attachment::offer(&app, &mail.attachments)?;
     let state = app.state::<Mutex<DeviceBridge>>();
     let mut guard = state.lock().await;
-    (*guard).set_msg(Message::PlanSync(plan))?;
+    let mail_str = serde_json::to_string(&mail).unwrap();
+    (*guard).set_msg(Message::Mail(mail_str))?;
     Ok(())
 }
 
+/// 把本机的全部历史作为下一条消息，双方都设置后碰一碰即可互相合并。
 #[command]
-pub async fn set_mail_msg(app: AppHandle, mail: Mail) -> Result<(), Error> {
+pub async fn set_history_sync_msg(app: AppHandle) -> Result<(), Error> {
+    let snapshot = sync::snapshot(&app)?;
     let state = app.state::<Mutex<DeviceBridge>>();
     let mut guard = state.lock().await;
-    let mail_str = serde_json::to_string(&mail).unwrap();
-    (*guard).set_msg(Message::Mail(mail_str))?;
+    (*guard).set_msg(Message::HistorySync(
+        serde_json::to_string(&snapshot).unwrap(),
+    ))?;
     Ok(())
 }
 
@@ -63,11 +108,17 @@ pub fn request_blep_bluetooth_permissions(app: AppHandle) -> Result<PermissionSt
         .map_err(|e| Error::RequestBlueTooth(e.to_string()))
 }
 
+/// 按 `nfc-error` 事件建议的补救措施打开系统设置。
+#[command]
+pub fn open_nfc_settings(app: AppHandle, target: SettingsTarget) -> Result<(), Error> {
+    app.nfc2()
+        .open_settings(target)
+        .map_err(|e| Error::NfcSettings(e.to_string()))
+}
+
 #[command]
 pub fn store_disposable_drafts(app: AppHandle, data: DisposableDrafts) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set("disposable-drafts", serde_json::to_value(&data).unwrap());
-    Ok(())
+    store_value(&app, "disposable-drafts", &data)
 }
 
 #[command]
@@ -82,11 +133,10 @@ pub fn load_disposable_drafts(app: AppHandle) -> Result<DisposableDrafts, Error>
     Ok(value)
 }
 
+/// 与已保存的记录合并而不是覆盖，以免冲掉同步过来但前端还没加载的记录。
 #[command]
 pub fn store_sealed_instances(app: AppHandle, data: SealedInstances) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set("sealed-instances", serde_json::to_value(&data).unwrap());
-    Ok(())
+    sync::merge_instances(&app, data.instances).map(|_| ())
 }
 
 #[command]
@@ -115,16 +165,14 @@ pub fn load_plan_drafts(app: AppHandle) -> Result<PlanDrafts, Error> {
 
 #[command]
 pub fn store_plan_drafts(app: AppHandle, data: PlanDrafts) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set("plan-drafts", serde_json::to_value(&data).unwrap());
-    Ok(())
+    sync::stamp_plans(&app, app.state::<DeviceId>().0, &data.drafts)?;
+    store_value(&app, "plan-drafts", &data)
 }
 
+/// 与已保存的列表合并而不是覆盖，以免冲掉同步过来但前端还没加载的记录。
 #[command]
 pub fn store_finished_plan_list(app: AppHandle, data: FinishedPlanList) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set("finished-plan-list", serde_json::to_value(&data).unwrap());
-    Ok(())
+    sync::merge_finished(&app, data.list).map(|_| ())
 }
 
 #[command]
@@ -141,16 +189,12 @@ pub fn load_finished_plan_list(app: AppHandle) -> Result<FinishedPlanList, Error
 
 #[command]
 pub fn store_mail_inner(app: AppHandle, uuid: Uuid, data: MailInner) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set(
-        format!("mail-{}", uuid),
-        serde_json::to_value(&data).unwrap(),
-    );
-    Ok(())
+    store_value(&app, &format!("mail-{}", uuid), &data)
 }
 
 #[command]
 pub fn load_mail_inner(app: AppHandle, uuid: Uuid) -> Result<MailInner, Error> {
+    mailbox::ensure_unlocked(&app, uuid)?;
     let store = app.store("store.json").map_err(Into::<Error>::into)?;
     let value = store.get(format!("mail-{}", uuid));
     let value = if let Some(v) = value {
@@ -163,9 +207,9 @@ pub fn load_mail_inner(app: AppHandle, uuid: Uuid) -> Result<MailInner, Error> {
 
 #[command]
 pub fn store_mail_covers(app: AppHandle, data: MailCoverList) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set("mail-cover-list", serde_json::to_value(&data).unwrap());
-    Ok(())
+    let old: MailCoverList = load_or_default(&app, sync::INBOX_MAIL_LIST)?;
+    mailbox::send_opened_receipts(&app, &old, &data)?;
+    store_value(&app, "mail-cover-list", &data)
 }
 
 #[command]
@@ -182,12 +226,7 @@ pub fn load_mail_covers(app: AppHandle) -> Result<MailCoverList, Error> {
 
 #[command]
 pub fn store_mail_drafts_covers(app: AppHandle, data: MailCoverList) -> Result<(), Error> {
-    let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.set(
-        "mail-drafts-cover-list",
-        serde_json::to_value(&data).unwrap(),
-    );
-    Ok(())
+    store_value(&app, "mail-drafts-cover-list", &data)
 }
 
 #[command]
@@ -205,6 +244,14 @@ pub fn load_mail_drafts_covers(app: AppHandle) -> Result<MailCoverList, Error> {
 #[command]
 pub fn delete_mail(app: AppHandle, uuid: Uuid) -> Result<(), Error> {
     let store = app.store("store.json").map_err(Into::<Error>::into)?;
-    store.delete(format!("mail-{}", uuid));
+    let key = format!("mail-{}", uuid);
+    app.state::<SearchIndex>().update(&key, None);
+    store.delete(key);
     Ok(())
 }
+
+/// 读取寄出的信件。寄出的信件只会通过历史同步从对方的收件箱得到。
+#[command]
+pub fn load_sent_mail_covers(app: AppHandle) -> Result<MailCoverList, Error> {
+    load_or_default(&app, sync::SENT_MAIL_LIST)
+}
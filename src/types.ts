@@ -8,8 +8,35 @@ export interface DisposableDrafts {
 }
 
 export interface Instance {
+  id?: string;
   instance: string;
   time: string;
+  context?: SealContext;
+  devices?: string[];
+  signatures?: SealSignature[];
+}
+
+export interface Location {
+  latitude: number;
+  longitude: number;
+}
+
+export interface Weather {
+  condition: string;
+  temperature?: number;
+}
+
+export interface SealContext {
+  location?: Location;
+  weather?: Weather;
+  photo?: AttachmentMeta;
+}
+
+export interface SealSignature {
+  record: string;
+  device: string;
+  public_key: string;
+  signature: string;
 }
 
 export interface SealedInstances {
@@ -21,6 +48,7 @@ export interface PlanDrafts {
 }
 
 export interface FinishedPlan {
+  id?: string;
   plan: Plan;
   time: string;
 }
@@ -34,9 +62,33 @@ export interface SyncPlans {
   plans: Map<string, Plan>;
 }
 
+export enum Recurrence {
+  Daily = "Daily",
+  Weekly = "Weekly",
+  Monthly = "Monthly",
+  Yearly = "Yearly",
+}
+
+export interface ChecklistItem {
+  id: string;
+  text: string;
+  done: boolean;
+}
+
 export interface Plan {
   title: string;
   body: string;
+  due?: string;
+  recurrence?: Recurrence;
+  checklist?: ChecklistItem[];
+  confirmed_by?: string[];
+}
+
+export interface PlanConflict {
+  id: string;
+  local?: Plan;
+  remote?: Plan;
+  kept_remote: boolean;
 }
 
 export enum MessageType {
@@ -45,11 +97,38 @@ export enum MessageType {
   Empty,
   Seal,
   Mail,
+  HistorySync,
 }
 
 export interface Mail {
+  id?: string;
+  from?: string;
   cover: string;
   inner: MailInner;
+  unlock_at?: string;
+  attachments?: AttachmentMeta[];
+}
+
+export interface AttachmentMeta {
+  id: string;
+  name: string;
+  mime: string;
+  size: number;
+  sha256: string;
+}
+
+export interface TransferProgress {
+  id: string;
+  direction: "Send" | "Receive";
+  done: number;
+  total: number;
+}
+
+export interface AttachmentProgress {
+  id: string;
+  direction: "Send" | "Receive";
+  done: number;
+  total: number;
 }
 
 export interface MailInner {
@@ -61,8 +140,126 @@ export interface MailCover {
   sealed: boolean;
   cover: string;
   timestamp: string;
+  unlock_at?: string;
+  peer?: string;
 }
 
 export interface MailCoverList {
   mails: Map<string, MailCover>;
 }
+
+export type ReceiptKind = "Delivered" | "Opened" | "Burned";
+
+export interface Receipt {
+  item: string;
+  kind: ReceiptKind;
+  at: string;
+}
+
+export interface ItemReceipts {
+  delivered?: string;
+  opened?: string;
+  burned?: string;
+}
+
+export type SearchKind = "Mail" | "Seal" | "Plan" | "FinishedPlan" | "Disposable";
+
+export interface SearchFilters {
+  kinds?: SearchKind[];
+  from?: string;
+  to?: string;
+  limit?: number;
+}
+
+export interface SearchHit {
+  kind: SearchKind;
+  id: string;
+  score: number;
+  snippet: string;
+  time?: string;
+}
+
+export type TimelineKind = "Seal" | "FinishedPlan" | "Mail";
+
+export interface TimelineEvent {
+  kind: TimelineKind;
+  id: string;
+  time: string;
+  direction: "Sent" | "Received" | "Shared";
+  peer?: string;
+  title: string;
+}
+
+export interface TimelinePage {
+  events: TimelineEvent[];
+  next?: number;
+  total: number;
+}
+
+export interface ReminderRules {
+  enabled: boolean;
+  anniversaries: boolean;
+  milestone_days: number[];
+  plan_due_days_before?: number;
+  mail_unlock: boolean;
+  notify_hour: number;
+}
+
+export type ReminderKind = "Anniversary" | "Milestone" | "PlanDue" | "MailUnlock";
+
+export interface Reminder {
+  key: string;
+  kind: ReminderKind;
+  at: string;
+  title: string;
+  body: string;
+}
+
+export type Period = "Week" | "Month" | "Year";
+
+export interface Counts {
+  taps: number;
+  letters_sent: number;
+  letters_received: number;
+  seals: number;
+  plans_completed: number;
+}
+
+export interface PeriodStats extends Counts {
+  start: string;
+}
+
+export interface PeerStats extends Counts {
+  peer: string;
+  last_tap?: string;
+}
+
+export interface Insights {
+  first_tap?: string;
+  days_since_first_tap?: number;
+  current_streak: number;
+  longest_streak: number;
+  total: Counts;
+  periods: PeriodStats[];
+  peers: PeerStats[];
+}
+
+export type NfcErrorKind = "NotSupported" | "Disabled" | "Security" | "Tag" | "Io" | { Unknown: string };
+
+export type Recovery = "Unavailable" | "OpenNfcSettings" | "OpenAppSettings" | "Retry" | "HoldLonger";
+
+export interface NfcError {
+  kind: NfcErrorKind;
+  message: string;
+  recovery: Recovery;
+}
+
+export interface ReaderStatus {
+  supported: boolean;
+  enabled: boolean;
+  initialized: boolean;
+  paused: boolean;
+  paused_by_user: boolean;
+  in_session: boolean;
+  init_error?: string;
+}
@@ -37,7 +37,7 @@ export async function genRandomSeal() {
   const sealed: SealedInstances = { instances: [] };
   for (let i = 0; i < 5; i++) {
     const rand: Instance = {
-      time: "2077-4-1 00:00:00",
+      time: "2077-04-01T00:00:00Z",
       instance: randString(),
     };
     sealed.instances.push(rand);
@@ -59,9 +59,12 @@ export async function genRandomPlan() {
 }
 
 export function getTimeStamp() {
-  const d = new Date();
-  const timestamp = d.toLocaleDateString() + " " + d.toLocaleTimeString();
-  return timestamp;
+  return new Date().toISOString();
+}
+
+export function formatTime(time: string) {
+  const d = new Date(time);
+  return d.toLocaleDateString() + " " + d.toLocaleTimeString();
 }
 
 export function randomUUID(): string {
@@ -14,6 +14,7 @@ import java.util.*
 class BlePeripheralUtils(private val context: Context) {
     companion object {
         private const val TAG = "BlePeripheralUtils"
+        private const val MAX_ATTRIBUTE_LEN = 512
     }
 
     private var mBluetoothAdapter: BluetoothAdapter? = null
@@ -22,6 +23,9 @@ class BlePeripheralUtils(private val context: Context) {
     private var bluetoothGattServer: BluetoothGattServer? = null
 
     private val deviceArrayList = ArrayList<BluetoothDevice>()
+
+    // 长写入的各段，按设备和特征拼接，执行写入时才作为一次写入交给回调
+    private val preparedWrites = HashMap<String, LinkedHashMap<BluetoothGattCharacteristic, ByteArray>>()
     var blePeripheralCallback: BlePeripheralCallback? = null
 
     fun getConnectedDevices(): List<BluetoothDevice> = deviceArrayList.toList()
@@ -46,6 +50,7 @@ class BlePeripheralUtils(private val context: Context) {
                 deviceArrayList.add(device)
             } else {
                 deviceArrayList.removeAll { it.address == device.address }
+                synchronized(preparedWrites) { preparedWrites.remove(device.address) }
             }
 
             blePeripheralCallback?.onConnectionStateChange(device, status, newState)
@@ -65,7 +70,10 @@ class BlePeripheralUtils(private val context: Context) {
         ) {
             Log.e(TAG, "onCharacteristicReadRequest：device name = ${device.name}, address = ${device.address}")
             Log.e(TAG, "onCharacteristicReadRequest：requestId = $requestId, offset = $offset")
-            bluetoothGattServer?.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, offset, characteristic.value)
+            // 较长的值会分多次读取，每次从 offset 开始
+            val value = characteristic.value ?: byteArrayOf()
+            val chunk = if (offset < value.size) value.copyOfRange(offset, value.size) else byteArrayOf()
+            bluetoothGattServer?.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, offset, chunk)
         }
 
         override fun onCharacteristicWriteRequest(
@@ -78,8 +86,18 @@ class BlePeripheralUtils(private val context: Context) {
             requestBytes: ByteArray
         ) {
             Log.e(TAG, "3.onCharacteristicWriteRequest：device name = ${device.name}, address = ${device.address}")
-            Log.e(TAG, "3.onCharacteristicWriteRequest：requestId = $requestId, preparedWrite=$preparedWrite, responseNeeded=$responseNeeded, offset=$offset, value=${String(requestBytes)}")
-            bluetoothGattServer?.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, offset, characteristic.value)
+            Log.e(TAG, "3.onCharacteristicWriteRequest：requestId = $requestId, preparedWrite=$preparedWrite, responseNeeded=$responseNeeded, offset=$offset, size=${requestBytes.size}")
+            if (preparedWrite) {
+                val status = prepareWrite(device, characteristic, offset, requestBytes)
+                // 准备写入的响应要原样带回这一段，主端据此校验
+                if (responseNeeded) {
+                    bluetoothGattServer?.sendResponse(device, requestId, status, offset, requestBytes)
+                }
+                return
+            }
+            if (responseNeeded) {
+                bluetoothGattServer?.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, offset, null)
+            }
             blePeripheralCallback?.onCharacteristicWriteRequest(device, requestId, characteristic, preparedWrite, responseNeeded, offset, requestBytes)
         }
 
@@ -117,11 +135,38 @@ class BlePeripheralUtils(private val context: Context) {
         override fun onMtuChanged(device: BluetoothDevice, mtu: Int) {
             super.onMtuChanged(device, mtu)
             Log.e(TAG, "onMtuChanged：mtu = $mtu")
+            blePeripheralCallback?.onMtuChanged(device, mtu)
         }
 
         override fun onExecuteWrite(device: BluetoothDevice, requestId: Int, execute: Boolean) {
             super.onExecuteWrite(device, requestId, execute)
-            Log.e(TAG, "onExecuteWrite：requestId = $requestId")
+            Log.e(TAG, "onExecuteWrite：requestId = $requestId, execute = $execute")
+            val writes = synchronized(preparedWrites) { preparedWrites.remove(device.address) }
+            bluetoothGattServer?.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, 0, null)
+            // 主端取消时丢弃已经准备的各段
+            if (!execute) return
+            writes?.forEach { (characteristic, value) ->
+                blePeripheralCallback?.onCharacteristicWriteRequest(device, requestId, characteristic, false, false, 0, value)
+            }
+        }
+    }
+
+    // 收下长写入的一段，返回给主端的状态。各段必须按顺序到达，总长不能超过属性值的上限。
+    private fun prepareWrite(
+        device: BluetoothDevice,
+        characteristic: BluetoothGattCharacteristic,
+        offset: Int,
+        value: ByteArray
+    ): Int = synchronized(preparedWrites) {
+        val writes = preparedWrites.getOrPut(device.address) { LinkedHashMap() }
+        val buffer = writes[characteristic] ?: byteArrayOf()
+        when {
+            offset != buffer.size -> BluetoothGatt.GATT_INVALID_OFFSET
+            buffer.size + value.size > MAX_ATTRIBUTE_LEN -> BluetoothGatt.GATT_INVALID_ATTRIBUTE_LENGTH
+            else -> {
+                writes[characteristic] = buffer + value
+                BluetoothGatt.GATT_SUCCESS
+            }
         }
     }
 
@@ -145,12 +190,19 @@ class BlePeripheralUtils(private val context: Context) {
         return false
     }
 
-    fun startBluetoothLeAdvertiser(bleName: String, serviceData: ByteArray, parcelUUID: UUID) {
+    fun startBluetoothLeAdvertiser(
+        bleName: String,
+        serviceData: ByteArray,
+        parcelUUID: UUID,
+        advertiseMode: Int = AdvertiseSettings.ADVERTISE_MODE_LOW_LATENCY,
+        txPowerLevel: Int = AdvertiseSettings.ADVERTISE_TX_POWER_MEDIUM,
+        timeoutMillis: Int = 0
+    ): Boolean {
         val settings = AdvertiseSettings.Builder()
             .setConnectable(true)
-            .setTimeout(0)
-            .setAdvertiseMode(AdvertiseSettings.ADVERTISE_MODE_LOW_LATENCY)
-            .setTxPowerLevel(AdvertiseSettings.ADVERTISE_TX_POWER_MEDIUM)
+            .setTimeout(timeoutMillis)
+            .setAdvertiseMode(advertiseMode)
+            .setTxPowerLevel(txPowerLevel)
             .build()
 
         val advertiseData = AdvertiseData.Builder()
@@ -164,16 +216,21 @@ class BlePeripheralUtils(private val context: Context) {
             .build()
 
         mBluetoothAdapter?.name = bleName
-        bluetoothLeAdvertiser = mBluetoothAdapter?.bluetoothLeAdvertiser
+        bluetoothLeAdvertiser = mBluetoothAdapter?.bluetoothLeAdvertiser ?: return false
         bluetoothLeAdvertiser?.startAdvertising(settings, advertiseData, scanResponseData, callback)
+        return true
     }
 
     fun stopBluetoothLeAdvertiser() {
         bluetoothLeAdvertiser?.stopAdvertising(callback)
     }
 
+    // 重新广播时沿用已经打开的 GATT 服务，只替换其中的服务
     fun addServices(vararg serviceInfo: BluetoothGattServiceInfo) {
-        bluetoothGattServer = bluetoothManager?.openGattServer(context, bluetoothGattServerCallback)
+        if (bluetoothGattServer == null) {
+            bluetoothGattServer = bluetoothManager?.openGattServer(context, bluetoothGattServerCallback)
+        }
+        bluetoothGattServer?.clearServices()
         serviceInfo.forEach { info ->
             val service = BluetoothGattService(info.uuid, info.serviceType)
             info.characteristicInfos.forEach { charInfo ->
@@ -182,6 +239,7 @@ class BlePeripheralUtils(private val context: Context) {
                     charInfo.properties,
                     charInfo.permissions
                 )
+                charInfo.value?.let { characteristic.value = it }
                 charInfo.descriptorInfo?.let { descInfo ->
                     characteristic.addDescriptor(
                         BluetoothGattDescriptor(descInfo.uuid, descInfo.permissions)
@@ -228,6 +286,7 @@ class BlePeripheralUtils(private val context: Context) {
             offset: Int,
             value: ByteArray
         )
+        fun onMtuChanged(device: BluetoothDevice, mtu: Int) {}
     }
 
     // Helper data classes (assuming these are defined in original Java code)
@@ -241,7 +300,8 @@ class BlePeripheralUtils(private val context: Context) {
         val uuid: UUID,
         val properties: Int,
         val permissions: Int,
-        val descriptorInfo: BluetoothGattDescriptorInfo?
+        val descriptorInfo: BluetoothGattDescriptorInfo?,
+        val value: ByteArray? = null
     )
 
     data class BluetoothGattDescriptorInfo(
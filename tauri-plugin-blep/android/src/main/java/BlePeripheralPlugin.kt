@@ -3,13 +3,21 @@ package com.plugin.blep
 import android.Manifest
 import android.app.Activity
 import android.bluetooth.BluetoothDevice
+import android.bluetooth.BluetoothGatt
+import android.bluetooth.BluetoothGattCallback
 import android.bluetooth.BluetoothGattCharacteristic
 import android.bluetooth.BluetoothGattDescriptor
 import android.bluetooth.BluetoothGattService
+import android.bluetooth.BluetoothManager
 import android.bluetooth.BluetoothProfile
 import android.bluetooth.le.AdvertiseCallback
+import android.bluetooth.le.AdvertiseCallback.*
 import android.bluetooth.le.AdvertiseSettings
+import android.content.Context
 import android.content.pm.PackageManager
+import android.os.Handler
+import android.os.Looper
+import android.util.Base64
 import android.util.Log
 import androidx.core.content.ContextCompat
 import app.tauri.annotation.Command
@@ -22,16 +30,47 @@ import app.tauri.plugin.JSObject
 import app.tauri.plugin.Plugin
 import java.util.*
 
+@InvokeArg
+class AdvertiseArgs {
+    var uuid: String? = null
+    var txPower: String = "Medium"
+    var mode: String = "LowLatency"
+    var timeoutMs: Int = 0
+}
+
+@InvokeArg
+class CharacteristicArgs {
+    lateinit var role: String
+    lateinit var uuid: String
+    var properties: Array<String> = arrayOf()
+    var value: String? = null
+}
+
+@InvokeArg
+class ServiceArgs {
+    var characteristics: Array<CharacteristicArgs> = arrayOf()
+}
+
 @InvokeArg
 class WatchArgs {
     lateinit var channel: Channel
     lateinit var connectNotifier: Channel
     lateinit var uuid: String
+    lateinit var service: ServiceArgs
+    var advertise: AdvertiseArgs = AdvertiseArgs()
 }
 
 @InvokeArg
 class SendArgs {
-    lateinit var message: String
+    // base64 编码的内容
+    lateinit var data: String
+    lateinit var characteristic: String
+}
+
+@InvokeArg
+class MtuRequestArgs {
+    lateinit var address: String
+    var mtu: Int = 517
 }
 
 @TauriPlugin(
@@ -50,6 +89,8 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
 
     companion object {
         private const val PERMISSION_REQUEST_CODE = 123
+        private const val DEFAULT_MTU = 23
+        private const val MTU_REQUEST_TIMEOUT_MS = 3000L
         private val REQUIRED_PERMISSIONS =
                 mutableListOf(
                         Manifest.permission.BLUETOOTH_CONNECT,
@@ -59,23 +100,36 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
     }
 
     private lateinit var blePeripheral: BlePeripheralUtils
-    private lateinit var notifyCharacteristic: BluetoothGattCharacteristic
+    private var serviceDef = ServiceArgs()
     private var recvChannel: Channel? = null
     private var connectChannel: Channel? = null
     private var customUuid: String? = null
 
+    // 广播状态，启动结果在 AdvertiseCallback 中才知道
+    private var advertising = false
+    private var advertisingUuid: String? = null
+    private var advertiseError: String? = null
+    private var advertiseStartedAt = 0L
+    private var advertiseTimeoutMs = 0
+    private var pendingAdvertise: Invoke? = null
+
+    // 主端请求后协商好的 MTU，从端不能主动请求
+    private var mtu = DEFAULT_MTU
+
     private val connectedDevice: BluetoothDevice?
         get() = blePeripheral.getConnectedDevices().firstOrNull()
 
     init {}
 
-    private fun checkAndRequestPermissions() {
+    private fun checkAndRequestPermissions(invoke: Invoke, options: AdvertiseArgs) {
         Log.i("ble peri", "checking")
         if (hasAllPermissions()) {
             Log.i("ble peri", "has all permission")
             setupBlePeripheral()
+            beginAdvertising(invoke, options)
         } else {
             Log.i("ble peri", "no permission")
+            invoke.reject("Missing required permissions")
         }
     }
 
@@ -102,7 +156,247 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
         recvChannel = args.channel
         connectChannel = args.connectNotifier
         customUuid = args.uuid
-        checkAndRequestPermissions()
+        serviceDef = args.service
+        checkAndRequestPermissions(invoke, args.advertise)
+    }
+
+    @Command
+    fun startAdvertising(invoke: Invoke) {
+        if (!::blePeripheral.isInitialized) {
+            invoke.reject("Advertising before setup")
+            return
+        }
+        beginAdvertising(invoke, invoke.parseArgs(AdvertiseArgs::class.java))
+    }
+
+    @Command
+    fun stopAdvertising(invoke: Invoke) {
+        if (::blePeripheral.isInitialized) {
+            try {
+                blePeripheral.stopBluetoothLeAdvertiser()
+            } catch (e: SecurityException) {
+                invoke.reject("Missing required permissions")
+                return
+            }
+        }
+        advertising = false
+        Log.i("ble ad", "stopped")
+        invoke.resolve(currentAdvertisingStatus())
+    }
+
+    @Command
+    fun mtu(invoke: Invoke) {
+        invoke.resolve(JSObject().apply { put("mtu", mtu) })
+    }
+
+    // 作为主端请求 MTU。另开一个 GATT 客户端连到已经连接的从端，MTU 属于整条连接，
+    // 其他插件建立的主端连接同样生效。请求失败或超时返回错误。
+    @Command
+    fun requestMtu(invoke: Invoke) {
+        val args = invoke.parseArgs(MtuRequestArgs::class.java)
+        val manager = activity.getSystemService(Context.BLUETOOTH_SERVICE) as BluetoothManager
+        val device =
+                try {
+                    manager.adapter.getRemoteDevice(args.address)
+                } catch (e: IllegalArgumentException) {
+                    invoke.reject("Invalid address ${args.address}")
+                    return
+                }
+        var gatt: BluetoothGatt? = null
+        var finished = false
+        val finish = { mtu: Int? ->
+            synchronized(this) {
+                if (!finished) {
+                    finished = true
+                    try {
+                        gatt?.close()
+                    } catch (e: SecurityException) {}
+                    if (mtu != null) invoke.resolve(JSObject().apply { put("mtu", mtu) })
+                    else invoke.reject("MTU request failed")
+                }
+            }
+        }
+        Handler(Looper.getMainLooper()).postDelayed({ finish(null) }, MTU_REQUEST_TIMEOUT_MS)
+        try {
+            gatt =
+                    device.connectGatt(
+                            activity,
+                            false,
+                            object : BluetoothGattCallback() {
+                                override fun onConnectionStateChange(
+                                        gatt: BluetoothGatt,
+                                        status: Int,
+                                        newState: Int
+                                ) {
+                                    if (newState != BluetoothProfile.STATE_CONNECTED) {
+                                        finish(null)
+                                    } else if (!gatt.requestMtu(args.mtu)) {
+                                        finish(null)
+                                    }
+                                }
+
+                                override fun onMtuChanged(gatt: BluetoothGatt, mtu: Int, status: Int) {
+                                    Log.i("ble mtu", "requested ${args.mtu}, got $mtu, status $status")
+                                    finish(if (status == BluetoothGatt.GATT_SUCCESS) mtu else null)
+                                }
+                            },
+                            BluetoothDevice.TRANSPORT_LE
+                    )
+        } catch (e: SecurityException) {
+            finish(null)
+        }
+    }
+
+    private fun notifyConnected() {
+        connectChannel?.send(
+                JSObject().apply {
+                    put("type", "Connected")
+                    put("mtu", mtu)
+                }
+        )
+    }
+
+    @Command
+    fun advertisingStatus(invoke: Invoke) {
+        invoke.resolve(currentAdvertisingStatus())
+    }
+
+    private fun currentAdvertisingStatus(): JSObject {
+        // 设置了超时的广播由系统自动停止，不会有回调
+        if (advertising &&
+                        advertiseTimeoutMs > 0 &&
+                        System.currentTimeMillis() - advertiseStartedAt >= advertiseTimeoutMs
+        ) {
+            advertising = false
+        }
+        return JSObject().apply {
+            put("advertising", advertising)
+            put("uuid", advertisingUuid)
+            put("error", advertiseError)
+        }
+    }
+
+    // 按选项开始广播，结果在回调中返回给 invoke
+    private fun beginAdvertising(invoke: Invoke, options: AdvertiseArgs) {
+        val uuid = options.uuid ?: customUuid
+        val mode =
+                when (options.mode) {
+                    "LowPower" -> AdvertiseSettings.ADVERTISE_MODE_LOW_POWER
+                    "Balanced" -> AdvertiseSettings.ADVERTISE_MODE_BALANCED
+                    else -> AdvertiseSettings.ADVERTISE_MODE_LOW_LATENCY
+                }
+        val txPower =
+                when (options.txPower) {
+                    "UltraLow" -> AdvertiseSettings.ADVERTISE_TX_POWER_ULTRA_LOW
+                    "Low" -> AdvertiseSettings.ADVERTISE_TX_POWER_LOW
+                    "High" -> AdvertiseSettings.ADVERTISE_TX_POWER_HIGH
+                    else -> AdvertiseSettings.ADVERTISE_TX_POWER_MEDIUM
+                }
+        try {
+            if (advertising) {
+                blePeripheral.stopBluetoothLeAdvertiser()
+                advertising = false
+            }
+            pendingAdvertise?.reject("Advertising restarted")
+            pendingAdvertise = invoke
+            customUuid = uuid
+            advertiseTimeoutMs = options.timeoutMs
+            val started =
+                    blePeripheral.startBluetoothLeAdvertiser(
+                            "TauriBleDevice",
+                            byteArrayOf(),
+                            UUID.fromString(uuid),
+                            mode,
+                            txPower,
+                            options.timeoutMs
+                    )
+            if (!started) {
+                onAdvertiseResult(false, "Bluetooth LE advertising not supported")
+            }
+        } catch (e: SecurityException) {
+            onAdvertiseResult(false, "Missing required permissions")
+        } catch (e: IllegalArgumentException) {
+            onAdvertiseResult(false, e.message ?: "Invalid advertise options")
+        }
+    }
+
+    private fun onAdvertiseResult(success: Boolean, error: String?) {
+        advertising = success
+        advertiseError = error
+        if (success) {
+            advertisingUuid = customUuid
+            advertiseStartedAt = System.currentTimeMillis()
+        }
+        pendingAdvertise?.let {
+            if (success) it.resolve(currentAdvertisingStatus()) else it.reject(error)
+        }
+        pendingAdvertise = null
+    }
+
+    private fun advertiseErrorMessage(errorCode: Int): String =
+            when (errorCode) {
+                ADVERTISE_FAILED_DATA_TOO_LARGE -> "Advertise data too large"
+                ADVERTISE_FAILED_TOO_MANY_ADVERTISERS -> "Too many advertisers"
+                ADVERTISE_FAILED_ALREADY_STARTED -> "Advertising already started"
+                ADVERTISE_FAILED_INTERNAL_ERROR -> "Internal error"
+                ADVERTISE_FAILED_FEATURE_UNSUPPORTED -> "Advertising not supported"
+                else -> "Unknown error $errorCode"
+            }
+
+    private val cccdUuid = UUID.fromString("00002902-0000-1000-8000-00805f9b34fb")
+
+    // 按 Rust 端的定义生成服务中的特征，可通知的特征带上 CCCD
+    private fun characteristicInfos(): List<BlePeripheralUtils.BluetoothGattCharacteristicInfo> =
+            serviceDef.characteristics.map { def ->
+                var properties = 0
+                var permissions = 0
+                for (property in def.properties) {
+                    when (property) {
+                        "Read" -> {
+                            properties = properties or BluetoothGattCharacteristic.PROPERTY_READ
+                            permissions = permissions or BluetoothGattCharacteristic.PERMISSION_READ
+                        }
+                        "Write" -> {
+                            properties = properties or BluetoothGattCharacteristic.PROPERTY_WRITE
+                            permissions =
+                                    permissions or BluetoothGattCharacteristic.PERMISSION_WRITE
+                        }
+                        "WriteWithoutResponse" -> {
+                            properties =
+                                    properties or
+                                            BluetoothGattCharacteristic
+                                                    .PROPERTY_WRITE_NO_RESPONSE
+                            permissions =
+                                    permissions or BluetoothGattCharacteristic.PERMISSION_WRITE
+                        }
+                        "Notify" ->
+                                properties = properties or BluetoothGattCharacteristic.PROPERTY_NOTIFY
+                    }
+                }
+                BlePeripheralUtils.BluetoothGattCharacteristicInfo(
+                        UUID.fromString(def.uuid),
+                        properties,
+                        permissions,
+                        if (properties and BluetoothGattCharacteristic.PROPERTY_NOTIFY != 0)
+                                BlePeripheralUtils.BluetoothGattDescriptorInfo(
+                                        cccdUuid,
+                                        BluetoothGattDescriptor.PERMISSION_READ or
+                                                BluetoothGattDescriptor.PERMISSION_WRITE
+                                )
+                        else null,
+                        def.value?.toByteArray()
+                )
+            }
+
+    private fun roleOf(uuid: UUID): String? =
+            serviceDef.characteristics.firstOrNull { UUID.fromString(it.uuid) == uuid }?.role
+
+    private fun characteristicOf(role: String): BluetoothGattCharacteristic? {
+        val def = serviceDef.characteristics.firstOrNull { it.role == role } ?: return null
+        return blePeripheral.getCharacteristic(
+                UUID.fromString(customUuid),
+                UUID.fromString(def.uuid)
+        )
     }
 
     private fun setupBlePeripheral() {
@@ -110,45 +404,32 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
             Log.e("BlePlugin", "Attempted setup without permissions")
             return
         }
+        // 沿用已有的实例，其中的 GATT 服务和广播也一并沿用；每次新建会留下关不掉的旧服务和广播。
+        // 回调读取的都是插件当前的字段，新会话的 uuid 和通道在重新广播时生效
+        if (::blePeripheral.isInitialized) {
+            if (advertising) {
+                blePeripheral.stopBluetoothLeAdvertiser()
+                advertising = false
+            }
+            return
+        }
 
         blePeripheral =
                 BlePeripheralUtils(activity).apply {
                     init()
 
-                    val serviceUuid = UUID.fromString(customUuid)
-                    val characteristicUuid = UUID.fromString(customUuid)
-
                     callback =
                             object : AdvertiseCallback() {
                                 override fun onStartSuccess(settingsInEffect: AdvertiseSettings) {
                                     Log.i("ble ad", "BLE advertisement added successfully")
-
                                     addServices(
                                             BlePeripheralUtils.BluetoothGattServiceInfo(
-                                                    serviceUuid,
+                                                    UUID.fromString(customUuid),
                                                     BluetoothGattService.SERVICE_TYPE_PRIMARY,
-                                                    listOf(
-                                                            BlePeripheralUtils
-                                                                    .BluetoothGattCharacteristicInfo(
-                                                                            characteristicUuid,
-                                                                            BluetoothGattCharacteristic
-                                                                                    .PROPERTY_WRITE or
-                                                                                    BluetoothGattCharacteristic
-                                                                                            .PROPERTY_NOTIFY,
-                                                                            BluetoothGattCharacteristic
-                                                                                    .PERMISSION_WRITE,
-                                                                            BlePeripheralUtils
-                                                                                    .BluetoothGattDescriptorInfo(
-                                                                                            UUID.fromString(
-                                                                                                    "00002902-0000-1000-8000-00805f9b34fb"
-                                                                                            ),
-                                                                                            BluetoothGattDescriptor
-                                                                                                    .PERMISSION_WRITE
-                                                                                    )
-                                                                    )
-                                                    )
+                                                    characteristicInfos()
                                             )
                                     )
+                                    onAdvertiseResult(true, null)
                                 }
 
                                 override fun onStartFailure(errorCode: Int) {
@@ -156,6 +437,7 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
                                             "ble ad",
                                             "Failed to add BLE advertisement, reason: $errorCode"
                                     )
+                                    onAdvertiseResult(false, advertiseErrorMessage(errorCode))
                                 }
                             }
 
@@ -167,16 +449,20 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
                                         newState: Int
                                 ) {
                                     if (newState == BluetoothProfile.STATE_DISCONNECTED) {
+                                        mtu = DEFAULT_MTU
                                         connectChannel?.send(
                                                 JSObject().apply { put("type", "Disconnected") }
                                         )
                                     } else if (newState == BluetoothProfile.STATE_CONNECTED) {
-                                        connectChannel?.send(
-                                                JSObject().apply { put("type", "Connected") }
-                                        )
+                                        notifyConnected()
                                     }
                                 }
 
+                                override fun onMtuChanged(device: BluetoothDevice, mtu: Int) {
+                                    this@BlePeripheralPlugin.mtu = mtu
+                                    notifyConnected()
+                                }
+
                                 override fun onCharacteristicWriteRequest(
                                         device: BluetoothDevice,
                                         requestId: Int,
@@ -186,9 +472,17 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
                                         offset: Int,
                                         value: ByteArray
                                 ) {
+                                    val role = roleOf(characteristic.uuid)
+                                    if (role == null) {
+                                        Log.w("ble per", "Write to unknown ${characteristic.uuid}")
+                                        return
+                                    }
                                     try {
                                         recvChannel?.send(
-                                                JSObject().apply { put("msg", String(value)) }
+                                                JSObject().apply {
+                                                    put("data", Base64.encodeToString(value, Base64.NO_WRAP))
+                                                    put("characteristic", role)
+                                                }
                                         )
                                     } catch (e: IllegalStateException) {
                                         recvChannel = null
@@ -196,7 +490,6 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
                                 }
                             }
 
-                    startBluetoothLeAdvertiser("TauriBleDevice", byteArrayOf(), serviceUuid)
                     Log.i("ble per", "started")
                 }
     }
@@ -208,21 +501,20 @@ class BlePeripheralPlugin(private val activity: Activity) : Plugin(activity) {
             return
         }
 
-        if (!::notifyCharacteristic.isInitialized) {
-            var serviceUuid = UUID.fromString(customUuid)
-            var characteristicUuid = UUID.fromString(customUuid)
-            notifyCharacteristic =
-                    blePeripheral?.getCharacteristic(serviceUuid, characteristicUuid)!!
+        if (!::blePeripheral.isInitialized) {
+            invoke.reject("Send before setup")
+            return
         }
 
         val args = invoke.parseArgs(SendArgs::class.java)
+        val characteristic = characteristicOf(args.characteristic)
         val success =
-                if (connectedDevice != null && ::notifyCharacteristic.isInitialized) {
+                if (connectedDevice != null && characteristic != null) {
                     try {
                         blePeripheral.notifyDevice(
                                 connectedDevice!!,
-                                notifyCharacteristic,
-                                args.message.toByteArray()
+                                characteristic,
+                                Base64.decode(args.data, Base64.NO_WRAP)
                         )
                     } catch (e: SecurityException) {
                         false
@@ -1,4 +1,5 @@
 use crate::error::Error;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::de::DeserializeOwned;
 use serde_json;
 use tauri::plugin::PermissionState;
@@ -25,15 +26,18 @@ pub fn init<R: Runtime, C: DeserializeOwned>(
 pub struct Blep<R: Runtime>(PluginHandle<R>);
 
 impl<R: Runtime> Blep<R> {
-    /// 设置插件  
-    /// 传入 message_sender 用于转发收到的信息，connect_notifier 用于转发连接的变化。
+    /// 按 `service` 建立 GATT 服务并按 `advertise` 开始广播，返回广播是否成功启动。
+    /// 传入 message_sender 用于转发收到的原始数据和写入的特征，由调用方解析；
+    /// connect_notifier 用于转发连接的变化。
     pub fn setup(
         &self,
-        message_sender: mpsc::UnboundedSender<Message>,
+        message_sender: mpsc::UnboundedSender<(CharacteristicRole, Vec<u8>)>,
         connect_notifier: watch::Sender<ConnectionStatus>,
         uuid: Uuid,
-    ) -> crate::Result<()> {
-        // 创建传输消息的 IPC channel，解析收到的消息后用 message_sender 转发。
+        service: GattService,
+        advertise: AdvertiseOptions,
+    ) -> crate::Result<AdvertisingStatus> {
+        // 创建传输消息的 IPC channel，解码收到的数据后用 message_sender 转发。
         let channel = Channel::new(move |event| {
             let payload = match event {
                 InvokeResponseBody::Json(payload) => serde_json::from_str::<RecvData>(&payload)
@@ -41,12 +45,14 @@ impl<R: Runtime> Blep<R> {
                     .map_err(Into::<tauri::Error>::into)?,
                 _ => panic!("Wrong return value from plugin-blep"),
             };
-            let payload = serde_json::from_str(&payload.msg)
+            let characteristic = payload.characteristic;
+            let payload = STANDARD
+                .decode(&payload.data)
                 .map_err(|e| Error::InvalidMessage(e.to_string()))
                 .map_err(Into::<tauri::Error>::into)?;
             let sender = message_sender.clone();
             sender
-                .send(payload)
+                .send((characteristic, payload))
                 .expect("send received ble peripheral message failed");
             Ok(())
         });
@@ -73,14 +79,71 @@ impl<R: Runtime> Blep<R> {
                     channel,
                     connect_notifier,
                     uuid,
+                    service,
+                    advertise,
+                },
+            )
+            .map_err(Into::into)
+    }
+
+    /// 通过指定的特征通知主端。
+    pub fn send(
+        &self,
+        characteristic: CharacteristicRole,
+        data: &[u8],
+    ) -> crate::Result<SendResponse> {
+        self.0
+            .run_mobile_plugin(
+                "send",
+                SendRequest {
+                    data: STANDARD.encode(data),
+                    characteristic,
                 },
             )
             .map_err(Into::into)
     }
 
-    pub fn send(&self, message: String) -> crate::Result<SendResponse> {
+    /// 以新的选项重新开始广播，已经在广播时先停止。需要先调用 `setup`。
+    pub fn start_advertising(&self, options: AdvertiseOptions) -> crate::Result<AdvertisingStatus> {
         self.0
-            .run_mobile_plugin("send", SendRequest { message })
+            .run_mobile_plugin("startAdvertising", options)
+            .map_err(Into::into)
+    }
+
+    /// 停止广播，已经建立的连接不受影响。
+    pub fn stop_advertising(&self) -> crate::Result<AdvertisingStatus> {
+        self.0
+            .run_mobile_plugin("stopAdvertising", ())
+            .map_err(Into::into)
+    }
+
+    pub fn advertising_status(&self) -> crate::Result<AdvertisingStatus> {
+        self.0
+            .run_mobile_plugin("advertisingStatus", ())
+            .map_err(Into::into)
+    }
+
+    /// 当前连接协商好的 MTU，没有连接时为默认值。从端不能发起协商，只能由主端请求。
+    pub fn mtu(&self) -> crate::Result<u16> {
+        self.0
+            .run_mobile_plugin::<MtuResponse>("mtu", ())
+            .map(|r| r.mtu)
+            .map_err(Into::into)
+    }
+
+    /// 作为主端向已经连接的 `address` 请求 MTU，返回协商的结果。会阻塞到协商完成或超时。
+    ///
+    /// MTU 属于整条连接，由其他插件建立的主端连接同样生效。
+    pub fn request_mtu(&self, address: &str, mtu: u16) -> crate::Result<u16> {
+        self.0
+            .run_mobile_plugin::<MtuResponse>(
+                "requestMtu",
+                MtuRequest {
+                    address: address.to_string(),
+                    mtu,
+                },
+            )
+            .map(|r| r.mtu)
             .map_err(Into::into)
     }
 
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use tauri::ipc::Channel;
 use tauri::plugin::PermissionState;
@@ -20,7 +21,9 @@ pub struct RequestPermission {
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SendRequest {
-    pub message: String,
+    /// base64 编码的内容，分段是二进制的，不能直接作为字符串传给插件
+    pub data: String,
+    pub characteristic: CharacteristicRole,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -32,7 +35,10 @@ pub struct SendResponse {
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RecvData {
-    pub msg: String,
+    /// base64 编码的内容
+    pub data: String,
+    /// 对方写入的特征
+    pub characteristic: CharacteristicRole,
 }
 
 #[derive(Serialize)]
@@ -41,46 +47,345 @@ pub struct WatchRecvPayload {
     pub channel: Channel,
     pub connect_notifier: Channel,
     pub uuid: String,
+    pub service: GattService,
+    pub advertise: AdvertiseOptions,
+}
+
+/// 特征的用途，方向以从端为准。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CharacteristicRole {
+    /// 握手和控制消息，双向
+    Control,
+    /// 从端发往主端的数据，主端订阅
+    DataTx,
+    /// 主端发往从端的数据
+    DataRx,
+    /// 只读的设备信息
+    DeviceInfo,
+}
+
+impl CharacteristicRole {
+    /// 发送数据的特征：从端通知主端，主端写入从端。
+    pub const fn data(from_peripheral: bool) -> Self {
+        if from_peripheral {
+            CharacteristicRole::DataTx
+        } else {
+            CharacteristicRole::DataRx
+        }
+    }
+
+    /// 各特征固定的 uuid，主端不必发现服务就知道往哪里写、订阅哪个。
+    pub const fn uuid(self) -> Uuid {
+        match self {
+            CharacteristicRole::Control => Uuid::from_u128(0x6f1c0001_5a3e_4c1b_9d0e_7a2b8c4d9e10),
+            CharacteristicRole::DataTx => Uuid::from_u128(0x6f1c0002_5a3e_4c1b_9d0e_7a2b8c4d9e10),
+            CharacteristicRole::DataRx => Uuid::from_u128(0x6f1c0003_5a3e_4c1b_9d0e_7a2b8c4d9e10),
+            CharacteristicRole::DeviceInfo => {
+                Uuid::from_u128(0x6f1c0004_5a3e_4c1b_9d0e_7a2b8c4d9e10)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CharacteristicProperty {
+    Read,
+    Write,
+    WriteWithoutResponse,
+    Notify,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CharacteristicDef {
+    pub role: CharacteristicRole,
+    pub uuid: Uuid,
+    pub properties: Vec<CharacteristicProperty>,
+    /// 可读特征的固定值
+    pub value: Option<String>,
+}
+
+/// 从端提供的 GATT 服务。服务的 uuid 跟随广播的 uuid。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GattService {
+    pub characteristics: Vec<CharacteristicDef>,
+}
+
+impl GattService {
+    /// 控制、收、发各一个特征，加上内容为 `device_info` 的只读特征。
+    pub fn standard(device_info: String) -> Self {
+        use CharacteristicProperty::*;
+        let def =
+            |role: CharacteristicRole, properties: Vec<CharacteristicProperty>| CharacteristicDef {
+                role,
+                uuid: role.uuid(),
+                properties,
+                value: None,
+            };
+        Self {
+            characteristics: vec![
+                def(
+                    CharacteristicRole::Control,
+                    vec![Write, WriteWithoutResponse, Notify],
+                ),
+                def(CharacteristicRole::DataTx, vec![Notify]),
+                def(
+                    CharacteristicRole::DataRx,
+                    vec![Write, WriteWithoutResponse],
+                ),
+                CharacteristicDef {
+                    value: Some(device_info),
+                    ..def(CharacteristicRole::DeviceInfo, vec![Read])
+                },
+            ],
+        }
+    }
+}
+
+/// 广播的发射功率，越高越容易被发现也越耗电。
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum TxPower {
+    UltraLow,
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+/// 广播间隔，对应 Android 的广播模式：`LowPower` 约 1 秒，`Balanced` 约 250 毫秒，`LowLatency` 约 100 毫秒。
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum AdvertiseMode {
+    LowPower,
+    Balanced,
+    #[default]
+    LowLatency,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdvertiseOptions {
+    /// 广播和服务使用的 uuid，为空时沿用 `setup` 传入的 uuid
+    pub uuid: Option<Uuid>,
+    pub tx_power: TxPower,
+    pub mode: AdvertiseMode,
+    /// 广播持续的毫秒数，超时后自动停止，0 表示不限。系统最多允许 180000。
+    pub timeout_ms: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdvertisingStatus {
+    pub advertising: bool,
+    pub uuid: Option<Uuid>,
+    /// 最近一次启动失败的原因
+    pub error: Option<String>,
+}
+
+/// 未协商时的默认 ATT MTU。
+pub const DEFAULT_MTU: u16 = 23;
+
+fn default_mtu() -> u16 {
+    DEFAULT_MTU
 }
 
 #[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum ConnectionStatus {
-    Connected,
+    /// 连接上后 MTU 每次变化都会再发一次。
+    Connected {
+        #[serde(default = "default_mtu")]
+        mtu: u16,
+    },
     Disconnected,
 }
 
+#[derive(Deserialize)]
+pub struct MtuResponse {
+    pub mtu: u16,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MtuRequest {
+    /// 已经连接的从端地址
+    pub address: String,
+    pub mtu: u16,
+}
+
 /// 手机间通信的信号
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum Message {
-    /// 一次性消息
+    /// 一次性消息，内容为序列化后的消息和查看时限
     Disposable(String),
-    /// 打卡“保存这一刻”
+    /// 打卡“保存这一刻”，内容为序列化后的打卡提议
     Seal(String),
+    /// 对双方共同打卡记录的签名
+    SealSign(String),
     /// 同步共同计划
     PlanSync(Plans),
     /// 信件
     Mail(String),
-    /// 没有消息需要传递。作为信号量通知 notification 监听已经建立，可以通信。
+    /// 同步双方全部历史记录，内容为序列化后的历史快照。
+    HistorySync(String),
+    /// 回执，告知对方发来的内容已送达、已拆开或已销毁。
+    Receipt(Receipt),
+    /// 请求对方从某个位置开始发送附件，用于断点续传。
+    AttachmentRequest(AttachmentRequest),
+    /// 附件的一块。
+    AttachmentChunk(AttachmentChunk),
+    /// 从端告知主端协商好的 MTU。主端请求失败时只能从这里知道。
+    Mtu(u16),
+    /// 主端已经订阅从端的通知，从端可以开始发送。连接后主端发出一次。
     ///
-    /// 即每次通信由主端先发送信息，发送信息表明监听已经建立，从端可以发送。
+    /// 内容为从端触碰令牌里的会话提示，从端核对无误才与主端收发。
+    Subscribed([u8; 8]),
+    /// 没有消息需要传递，触碰时没有设置消息就发出这条。
     Empty,
 }
 
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Receipt {
+    /// 回执对应的内容，为发送方生成的 uuid
+    pub item: Uuid,
+    pub kind: ReceiptKind,
+    /// 收方产生回执的时间
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ReceiptKind {
+    Delivered,
+    Opened,
+    Burned,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AttachmentRequest {
+    pub attachment: Uuid,
+    /// 已经收到的字节数
+    pub offset: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttachmentChunk {
+    pub attachment: Uuid,
+    /// 这一块在文件中的位置
+    pub offset: u64,
+    /// base64 编码的内容
+    pub data: String,
+}
+
+impl std::fmt::Debug for AttachmentChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentChunk")
+            .field("attachment", &self.attachment)
+            .field("offset", &self.offset)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Plans {
-    selected_plan: Option<Uuid>,
-    plans: HashMap<Uuid, Plan>,
+    pub selected_plan: Option<Uuid>,
+    pub plans: HashMap<Uuid, Plan>,
+    /// 每个计划的修订信息，已删除的计划只在这里留下墓碑。
+    #[serde(default)]
+    pub revisions: HashMap<Uuid, PlanRevision>,
+}
+
+/// 计划的修订信息，用于双方合并计划。
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct PlanRevision {
+    /// 最后一次修改者的 uuid
+    pub author: Uuid,
+    /// 最后一次修改的时间，毫秒时间戳
+    pub modified: u64,
+    /// 版本向量，记录每台设备修改这个计划的次数
+    pub version: HashMap<Uuid, u64>,
+    /// 计划是否已被删除
+    pub deleted: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Plan {
-    title: String,
-    body: String,
+    pub title: String,
+    pub body: String,
+    /// 目标日期，用于纪念日倒计时等
+    #[serde(default)]
+    pub due: Option<NaiveDate>,
+    /// 重复周期，完成后按周期生成下一次
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+    /// 子任务
+    #[serde(default)]
+    pub checklist: Vec<ChecklistItem>,
+    /// 已经确认完成的设备 uuid，双方都确认后计划才算完成
+    #[serde(default)]
+    pub confirmed_by: Vec<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChecklistItem {
+    pub id: Uuid,
+    pub text: String,
+    pub done: bool,
+}
+
+impl Plan {
+    /// 子任务的完成比例，没有子任务时为 `None`。
+    pub fn progress(&self) -> Option<f32> {
+        if self.checklist.is_empty() {
+            return None;
+        }
+        let done = self.checklist.iter().filter(|item| item.done).count();
+        Some(done as f32 / self.checklist.len() as f32)
+    }
+
+    /// 本机和触碰的对方是否都已确认完成。其他 uuid 的确认不算数。
+    pub fn is_confirmed_by_both(&self, device: Uuid, peer: Uuid) -> bool {
+        self.confirmed_by.contains(&device) && self.confirmed_by.contains(&peer)
+    }
 }
 
 impl Message {
     pub fn to_string(&self) -> String {
         serde_json::to_string(&self).unwrap()
     }
+
+    /// 发送这条消息使用的特征：握手和控制类消息走控制特征，其余走数据特征。
+    /// 附件块需要和所属附件的其他部分保持顺序，走数据特征。
+    pub fn characteristic(&self, from_peripheral: bool) -> CharacteristicRole {
+        match self {
+            Message::Empty
+            | Message::Receipt(_)
+            | Message::SealSign(_)
+            | Message::AttachmentRequest(_)
+            | Message::Mtu(_)
+            | Message::Subscribed(_) => CharacteristicRole::Control,
+            _ => CharacteristicRole::data(from_peripheral),
+        }
+    }
+
+    /// 控制消息跟在每次触碰的主消息后面发送，不代表一次触碰。
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Message::Receipt(_)
+                | Message::SealSign(_)
+                | Message::AttachmentRequest(_)
+                | Message::AttachmentChunk(_)
+                | Message::Mtu(_)
+                | Message::Subscribed(_)
+        )
+    }
 }
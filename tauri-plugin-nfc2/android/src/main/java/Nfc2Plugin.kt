@@ -10,7 +10,11 @@ import android.nfc.*
 import android.nfc.NfcAdapter
 import android.nfc.cardemulation.*
 import android.nfc.tech.IsoDep
+import android.nfc.tech.Ndef
+import android.nfc.tech.NdefFormatable
+import android.net.Uri
 import android.os.Bundle
+import android.provider.Settings
 import android.util.Log
 import app.tauri.annotation.Command
 import app.tauri.annotation.InvokeArg
@@ -22,25 +26,47 @@ import kotlin.random.Random
 class WatchArgs {
     lateinit var dataChannel: Channel
     lateinit var errorChannel: Channel
-    lateinit var uuid: String
+    lateinit var tagChannel: Channel
+    lateinit var token: String
 }
 
 @InvokeArg
-class HceConfigArgs {
-    var aid: String = "F00000000A0101"
-    var uuid: String = "12345678123456781234567812345678"
+class WriteTagArgs {
+    lateinit var payload: String
+}
+
+// Whispact 记录的 NFC Forum 外部类型
+private const val TAG_DOMAIN = "whispact.app"
+private const val TAG_TYPE = "msg"
+
+
+@InvokeArg
+class TokenArgs {
+    lateinit var token: String
+}
+
+@InvokeArg
+class SettingsArgs {
+    // "nfc" 或 "app"
+    var target: String = "nfc"
 }
 
 @TauriPlugin
 class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
     // 状态变量声明
     private var isHceEnabled: Boolean = false
+    // 暂停时不读卡，HCE 也不响应，见 HceService
+    private var paused: Boolean = false
     private var currentAid: String = "F00000000A0101"
-    private var currentUuid: String = "12345678-1234-5678-1234-567812345678"
+    // 本机的签名令牌，十六进制，由 Rust 端定期更新
+    private var currentToken: String = ""
 
     private val nfcAdapter: NfcAdapter? = NfcAdapter.getDefaultAdapter(activity)
     private var dataChannel: Channel? = null
     private var errorChannel: Channel? = null
+    private var tagChannel: Channel? = null
+    // 等待写入标签的请求，下次触碰标签时写入后再 resolve
+    private var pendingWrite: Pair<Invoke, ByteArray>? = null
     private lateinit var prefs: SharedPreferences
 
     private val sharedPreferencesChangeListener =
@@ -50,9 +76,10 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
                     val da =
                             sharedPreferences.getString(key, "")
                                     ?: return@OnSharedPreferenceChangeListener
-                    if (da.length >= 32) {
-                        val truncatedData = da.substring(0, 32)
-                        sendData(truncatedData)
+                    // 值为 "<令牌>:<随机数>"，随机数保证每次被读都会触发变化
+                    val token = da.substringBefore(":")
+                    if (token.isNotEmpty()) {
+                        sendData(token)
                     }
                 }
             }
@@ -71,7 +98,8 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
         val args = invoke.parseArgs(WatchArgs::class.java)
         dataChannel = args.dataChannel
         errorChannel = args.errorChannel
-        currentUuid = args.uuid
+        tagChannel = args.tagChannel
+        currentToken = args.token
         prefs = activity.getSharedPreferences("nfc_plugin", Activity.MODE_PRIVATE)
         saveHceConfig()
         // val cardEmulation = CardEmulation.getInstance(NfcAdapter.getDefaultAdapter(activity))
@@ -87,6 +115,85 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
         checkNfcStatus()
         prefs.registerOnSharedPreferenceChangeListener(sharedPreferencesChangeListener)
     }
+    @Command
+    fun setToken(invoke: Invoke) {
+        val args = invoke.parseArgs(TokenArgs::class.java)
+        currentToken = args.token
+        if (::prefs.isInitialized) {
+            saveHceConfig()
+        }
+        invoke.resolve()
+    }
+
+    @Command
+    fun openSettings(invoke: Invoke) {
+        val args = invoke.parseArgs(SettingsArgs::class.java)
+        val intent =
+                when (args.target) {
+                    "app" ->
+                            Intent(Settings.ACTION_APPLICATION_DETAILS_SETTINGS).apply {
+                                data = Uri.fromParts("package", activity.packageName, null)
+                            }
+                    else -> Intent(Settings.ACTION_NFC_SETTINGS)
+                }
+        activity.startActivity(intent.addFlags(Intent.FLAG_ACTIVITY_NEW_TASK))
+        invoke.resolve()
+    }
+
+    @Command
+    fun writeTag(invoke: Invoke) {
+        val args = invoke.parseArgs(WriteTagArgs::class.java)
+        pendingWrite?.first?.reject("CANCELLED")
+        pendingWrite = invoke to args.payload.hexToBytes()
+        activity.runOnUiThread { checkNfcStatus() }
+    }
+
+    @Command
+    fun cancelWriteTag(invoke: Invoke) {
+        pendingWrite?.first?.reject("CANCELLED")
+        pendingWrite = null
+        invoke.resolve()
+    }
+
+    @Command
+    fun pause(invoke: Invoke) {
+        paused = true
+        if (::prefs.isInitialized) {
+            prefs.edit().putBoolean("paused", true).apply()
+        }
+        activity.runOnUiThread {
+            try {
+                nfcAdapter?.disableForegroundDispatch(activity)
+            } catch (e: IllegalStateException) {
+                // Activity 不在前台时本来就没有在读卡
+                Log.i("pause", "${e.message}")
+            }
+        }
+        invoke.resolve()
+    }
+
+    @Command
+    fun resume(invoke: Invoke) {
+        paused = false
+        if (::prefs.isInitialized) {
+            prefs.edit().putBoolean("paused", false).apply()
+            activity.runOnUiThread { checkNfcStatus() }
+        }
+        invoke.resolve()
+    }
+
+    @Command
+    fun status(invoke: Invoke) {
+        invoke.resolve(
+                JSObject().apply {
+                    put("supported", nfcAdapter != null)
+                    put("enabled", nfcAdapter?.isEnabled == true)
+                    put("initialized", dataChannel != null)
+                    put("paused", paused)
+                }
+        )
+    }
+
     @Command
     fun stopHce(invoke: Invoke) {
         isHceEnabled = false
@@ -94,8 +201,12 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
     }
 
     private fun saveHceConfig() {
-        prefs.edit().putString("aid", currentAid).putString("uuid", currentUuid).apply()
-        Log.i("hce save", prefs.getString("uuid", "")!!)
+        prefs.edit()
+                .putString("aid", currentAid)
+                .putString("token", currentToken)
+                .putBoolean("paused", paused)
+                .apply()
+        Log.i("hce save", "token updated")
     }
 
     private fun checkNfcStatus() {
@@ -113,7 +224,13 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
                         addCategory(Intent.CATEGORY_DEFAULT)
                     }
             val filters = arrayOf(intentFilter)
-            val techList = arrayOf(arrayOf(IsoDep::class.java.name)) // 仅处理 ISO-DEP 标签
+            // ISO-DEP 用于手机之间，NDEF 用于读写贴纸
+            val techList =
+                    arrayOf(
+                            arrayOf(IsoDep::class.java.name),
+                            arrayOf(Ndef::class.java.name),
+                            arrayOf(NdefFormatable::class.java.name)
+                    )
 
             nfcAdapter?.enableForegroundDispatch(activity, pendingIntent, filters, techList)
         } catch (e: SecurityException) {
@@ -131,6 +248,16 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
     }
 
     private fun processTag(tag: Tag) {
+        pendingWrite?.let { (invoke, payload) ->
+            pendingWrite = null
+            writeNdef(tag, invoke, payload)
+            return
+        }
+        if (paused) return
+        Ndef.get(tag)?.let {
+            readNdef(it)
+            return
+        }
         IsoDep.get(tag)?.use { isoDep ->
             try {
                 Log.i("handle intent", "start")
@@ -141,16 +268,13 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
                 val selectResponse = isoDep.transceive(selectApdu)
                 if (!isSuccess(selectResponse)) return@use
 
-                Log.i("handle intent", "00CA0000${currentUuid}")
-                val uuidResponse = isoDep.transceive("00CA0000${currentUuid}".hexToBytes())
-                if (!isSuccess(uuidResponse)) return@use
+                // GET DATA，数据是本机令牌，对方回复它的令牌
+                val lc = String.format("%02X", currentToken.length / 2)
+                val tokenResponse = isoDep.transceive("00CA0000${lc}${currentToken}00".hexToBytes())
+                if (!isSuccess(tokenResponse)) return@use
                 Log.i("handle intent", "geted")
-                val uuid =
-                        uuidResponse
-                                .copyOfRange(0, uuidResponse.size - 2)
-                                .toHexString()
-                                .insertHyphens()
-                sendData(uuid)
+                val token = tokenResponse.copyOfRange(0, tokenResponse.size - 2).toHexString()
+                sendData(token)
             } catch (e: Exception) {
                 sendError("IO_ERROR", "通信失败: ${e.message}")
             }
@@ -158,6 +282,69 @@ class Nfc2Plugin(private val activity: Activity) : Plugin(activity) {
                 ?: sendError("TAG_ERROR", "不支持的标签类型")
     }
 
+    private fun whispactMessage(payload: ByteArray) =
+            NdefMessage(
+                    arrayOf(
+                            NdefRecord.createExternal(TAG_DOMAIN, TAG_TYPE, payload),
+                            // 没装应用的手机读到时打开应用商店
+                            NdefRecord.createApplicationRecord(activity.packageName)
+                    )
+            )
+
+    private fun writeNdef(tag: Tag, invoke: Invoke, payload: ByteArray) {
+        val message = whispactMessage(payload)
+        try {
+            val ndef = Ndef.get(tag)
+            if (ndef != null) {
+                ndef.use {
+                    it.connect()
+                    when {
+                        !it.isWritable -> invoke.reject("TAG_READ_ONLY")
+                        message.byteArrayLength > it.maxSize -> invoke.reject("TAG_TOO_SMALL")
+                        else -> {
+                            it.writeNdefMessage(message)
+                            invoke.resolve()
+                        }
+                    }
+                }
+                return
+            }
+            val formatable = NdefFormatable.get(tag)
+            if (formatable != null) {
+                formatable.use {
+                    it.connect()
+                    it.format(message)
+                    invoke.resolve()
+                }
+                return
+            }
+            invoke.reject("TAG_ERROR")
+        } catch (e: Exception) {
+            invoke.reject("IO_ERROR: ${e.message}")
+        }
+    }
+
+    private fun readNdef(ndef: Ndef) {
+        try {
+            ndef.use {
+                it.connect()
+                val message = it.ndefMessage ?: it.cachedNdefMessage
+                val record =
+                        message?.records?.firstOrNull { r ->
+                            r.tnf == NdefRecord.TNF_EXTERNAL_TYPE &&
+                                    String(r.type) == "$TAG_DOMAIN:$TAG_TYPE"
+                        }
+                if (record == null) {
+                    sendError("TAG_ERROR", "不是 Whispact 标签")
+                } else {
+                    tagChannel?.send(JSObject().apply { put("value", record.payload.toHexString()) })
+                }
+            }
+        } catch (e: Exception) {
+            sendError("IO_ERROR", "读取标签失败: ${e.message}")
+        }
+    }
+
     private fun isSuccess(response: ByteArray): Boolean {
         Log.i("nfc response", response.toHexString())
         return response.size >= 2 &&
@@ -203,15 +390,24 @@ class HceService : HostApduService() {
                 }
             }
             0xCA -> {
-                if (apdu.size >= 4 && apdu[2] == 0x00.toByte() && apdu[3] == 0x00.toByte()) {
-                    val randomBytes = Random.nextBytes(60)
-                    val res = "${apdu.toHexString().substring(8)}${randomBytes.toHexString()}"
-                    Log.i("read hce", "${apdu.toHexString()}")
-                    Log.i("read hce", "${prefs.getString("uuid", "")!!.replace("-", "")}")
-                    Log.i("read hce", res)
-                    prefs.edit().putString("be_readed", res).commit()
-                    (prefs.getString("uuid", "")!!.replace("-", "").hexToBytes() +
-                            "9000".hexToBytes())
+                val token = prefs.getString("token", "")!!
+                if (prefs.getBoolean("paused", false)) {
+                    // 条件不满足，对方会当作读卡失败
+                    "6985".hexToBytes()
+                } else if (apdu.size >= 5 &&
+                                apdu[2] == 0x00.toByte() &&
+                                apdu[3] == 0x00.toByte() &&
+                                token.isNotEmpty()
+                ) {
+                    val lc = apdu[4].toInt() and 0xFF
+                    if (apdu.size < 5 + lc) return "6700".hexToBytes()
+                    val peerToken = apdu.copyOfRange(5, 5 + lc).toHexString()
+                    val randomBytes = Random.nextBytes(8)
+                    Log.i("read hce", "peer token received")
+                    prefs.edit()
+                            .putString("be_readed", "$peerToken:${randomBytes.toHexString()}")
+                            .commit()
+                    token.hexToBytes() + "9000".hexToBytes()
                 } else {
                     "6A86".hexToBytes()
                 }
@@ -234,6 +430,3 @@ class HceService : HostApduService() {
 private fun String.hexToBytes() = chunked(2).map { it.toInt(16).toByte() }.toByteArray()
 
 private fun ByteArray.toHexString() = joinToString("") { "%02X".format(it) }
-
-private fun String.insertHyphens() =
-        replace(Regex("(.{8})(.{4})(.{4})(.{4})(.{12})"), "$1-$2-$3-$4-$5")
@@ -5,8 +5,7 @@ use tauri::{
     plugin::{PluginApi, PluginHandle},
     AppHandle, Runtime,
 };
-use tokio::sync::{mpsc, watch};
-use uuid::Uuid;
+use tokio::sync::mpsc;
 
 use crate::models::*;
 
@@ -21,30 +20,53 @@ pub fn init<R: Runtime, C: DeserializeOwned>(
 
 pub struct Nfc2<R: Runtime>(PluginHandle<R>);
 
+/// 令牌在 Kotlin 和 APDU 中都以大写十六进制传递。
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// 把原生端发来的十六进制数据解码后转发。
+fn bytes_channel(sender: mpsc::UnboundedSender<Vec<u8>>) -> Channel {
+    Channel::new(move |event| {
+        let payload = if let InvokeResponseBody::Json(payload) = event {
+            serde_json::from_str(&payload).expect("could not deserialize nfc data response")
+        } else {
+            TokenResponse::default()
+        };
+        let payload = decode_hex(&payload.value)
+            .ok_or(Error::InvalidCard)
+            .map_err(Into::<tauri::Error>::into)?;
+        sender
+            .send(payload)
+            .expect("send received nfc message failed");
+        Ok(())
+    })
+}
+
 impl<R: Runtime> Nfc2<R> {
-    /// 初始化 nfc 读卡器，并设置 uuid。
-    /// - `uuid_sender`: 一个 watch 的 sender，内部值是最后一次 nfc 读到的对方 uuid。
-    /// - `error_sender`: 用于发送错误信息，错误信息可能有 NFC_NOT_SUPPORTED, NFC_DISABLED, SECURITY_ERROR, TAG_ERROR, IO_ERROR。
+    /// 初始化 nfc 读卡器，并设置本机的令牌。可以重复调用，新的 channel 会替换旧的。
+    /// - `token_sender`: 每次 nfc 读到对方令牌时发送，由调用方验证。
+    /// - `error_sender`: 用于发送解析后的错误信息，见 [`NfcErrorKind`]。
+    /// - `tag_sender`: 读到 Whispact NDEF 标签时发送记录内容，由调用方解码。
     pub fn init_nfc_reader(
         &self,
-        uuid_sender: watch::Sender<Uuid>,
-        error_sender: mpsc::UnboundedSender<NfcErrorResponse>,
-        uuid: Uuid,
+        token_sender: mpsc::UnboundedSender<Vec<u8>>,
+        error_sender: mpsc::UnboundedSender<NfcError>,
+        tag_sender: mpsc::UnboundedSender<Vec<u8>>,
+        token: &[u8],
     ) -> crate::Result<()> {
-        let data_channel = Channel::new(move |event| {
-            let payload = if let InvokeResponseBody::Json(payload) = event {
-                serde_json::from_str(&payload).expect("could not deserialize ble nfc uuid response")
-            } else {
-                UuidResponse::default()
-            };
-            let payload = Uuid::parse_str(&payload.value)
-                .map_err(|_| Error::InvalidCard)
-                .map_err(Into::<tauri::Error>::into)?;
-            uuid_sender
-                .send(payload)
-                .expect("send received nfc message failed");
-            Ok(())
-        });
+        let data_channel = bytes_channel(token_sender);
+        let tag_channel = bytes_channel(tag_sender);
 
         let error_channel = Channel::new(move |event| {
             let payload = if let InvokeResponseBody::Json(payload) = event {
@@ -53,21 +75,73 @@ impl<R: Runtime> Nfc2<R> {
                 NfcErrorResponse::default()
             };
             error_sender
-                .send(payload)
+                .send(NfcError::from(payload))
                 .expect("failed to send received nfc error");
             Ok(())
         });
 
-        let uuid = String::from(uuid.simple().encode_upper(&mut Uuid::encode_buffer()));
         self.0
             .run_mobile_plugin(
                 "init",
                 NfcRequest {
                     data_channel,
                     error_channel,
-                    uuid,
+                    tag_channel,
+                    token: encode_hex(token),
+                },
+            )
+            .map_err(Into::into)
+    }
+
+    /// 更新本机的令牌，之后被读和读卡时都使用新的令牌。
+    pub fn set_token(&self, token: &[u8]) -> crate::Result<()> {
+        self.0
+            .run_mobile_plugin(
+                "setToken",
+                TokenRequest {
+                    token: encode_hex(token),
+                },
+            )
+            .map_err(Into::into)
+    }
+
+    /// 打开系统设置页，用于 [`Recovery::OpenNfcSettings`] 和 [`Recovery::OpenAppSettings`]。
+    pub fn open_settings(&self, target: SettingsTarget) -> crate::Result<()> {
+        self.0
+            .run_mobile_plugin("openSettings", SettingsRequest { target })
+            .map_err(Into::into)
+    }
+
+    /// 暂停读卡和被读，已经初始化的状态保留。
+    pub fn pause_reader(&self) -> crate::Result<()> {
+        self.0.run_mobile_plugin("pause", ()).map_err(Into::into)
+    }
+
+    pub fn resume_reader(&self) -> crate::Result<()> {
+        self.0.run_mobile_plugin("resume", ()).map_err(Into::into)
+    }
+
+    pub fn reader_status(&self) -> crate::Result<ReaderStatus> {
+        self.0.run_mobile_plugin("status", ()).map_err(Into::into)
+    }
+
+    /// 等待下一次触碰 NDEF 标签并写入一条 Whispact 记录，写完或失败后返回。
+    ///
+    /// 会一直阻塞到用户触碰标签或调用 [`Nfc2::cancel_write_tag`]，不要在主线程调用。
+    pub fn write_tag(&self, payload: &[u8]) -> crate::Result<()> {
+        self.0
+            .run_mobile_plugin(
+                "writeTag",
+                WriteTagRequest {
+                    payload: encode_hex(payload),
                 },
             )
             .map_err(Into::into)
     }
+
+    pub fn cancel_write_tag(&self) -> crate::Result<()> {
+        self.0
+            .run_mobile_plugin("cancelWriteTag", ())
+            .map_err(Into::into)
+    }
 }
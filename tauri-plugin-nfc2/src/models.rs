@@ -3,7 +3,8 @@ use tauri::ipc::Channel;
 
 #[derive(Debug, Deserialize, Serialize, Default)]
 #[serde(rename_all = "camelCase")]
-pub struct UuidResponse {
+pub struct TokenResponse {
+    /// 十六进制编码的对方令牌，或标签上的记录
     pub value: String,
 }
 
@@ -12,13 +13,37 @@ pub struct UuidResponse {
 pub struct NfcRequest {
     pub error_channel: Channel,
     pub data_channel: Channel,
-    pub uuid: String,
+    /// 读到 Whispact NDEF 标签时发送记录内容
+    pub tag_channel: Channel,
+    pub token: String,
 }
 
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct HceRequest {
-    pub uuid: String,
+pub struct WriteTagRequest {
+    /// 十六进制编码的记录内容
+    pub payload: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRequest {
+    /// 十六进制编码的本机令牌
+    pub token: String,
+}
+
+/// 原生端读卡器的状态。
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReaderStatus {
+    /// 设备有 NFC
+    pub supported: bool,
+    /// NFC 已经打开
+    pub enabled: bool,
+    /// 已经调用过 `init`
+    pub initialized: bool,
+    /// 暂停时既不读卡也不响应对方的读卡
+    pub paused: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default)]
@@ -27,3 +52,93 @@ pub struct NfcErrorResponse {
     pub code: String,
     pub data: String,
 }
+
+/// 原生端报告的错误码。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum NfcErrorKind {
+    /// NFC_NOT_SUPPORTED：设备没有 NFC
+    NotSupported,
+    /// NFC_DISABLED：NFC 没有打开
+    Disabled,
+    /// SECURITY_ERROR：没有 NFC 权限
+    Security,
+    /// TAG_ERROR：读到的不是支持的标签，比如普通的卡片
+    Tag,
+    /// IO_ERROR：通信中途断开，多半是离开得太早
+    Io,
+    Unknown(String),
+}
+
+/// 建议用户采取的补救措施。
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Recovery {
+    /// 无法补救
+    Unavailable,
+    /// 打开系统的 NFC 设置
+    OpenNfcSettings,
+    /// 打开应用设置授予权限
+    OpenAppSettings,
+    /// 重新触碰
+    Retry,
+    /// 重新触碰并保持靠近久一点
+    HoldLonger,
+}
+
+impl NfcErrorKind {
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Self::NotSupported => Recovery::Unavailable,
+            Self::Disabled => Recovery::OpenNfcSettings,
+            Self::Security => Recovery::OpenAppSettings,
+            Self::Tag | Self::Unknown(_) => Recovery::Retry,
+            Self::Io => Recovery::HoldLonger,
+        }
+    }
+}
+
+impl From<&str> for NfcErrorKind {
+    fn from(code: &str) -> Self {
+        match code {
+            "NFC_NOT_SUPPORTED" => Self::NotSupported,
+            "NFC_DISABLED" => Self::Disabled,
+            "SECURITY_ERROR" => Self::Security,
+            "TAG_ERROR" => Self::Tag,
+            "IO_ERROR" => Self::Io,
+            other => Self::Unknown(other.to_string()),
+        }
+    }
+}
+
+/// 解析后的 NFC 错误，附带建议的补救措施。
+#[derive(Debug, Clone, Serialize)]
+pub struct NfcError {
+    pub kind: NfcErrorKind,
+    /// 原生端给出的描述
+    pub message: String,
+    pub recovery: Recovery,
+}
+
+impl From<NfcErrorResponse> for NfcError {
+    fn from(response: NfcErrorResponse) -> Self {
+        let kind = NfcErrorKind::from(response.code.as_str());
+        Self {
+            recovery: kind.recovery(),
+            kind,
+            message: response.data,
+        }
+    }
+}
+
+/// 要打开的系统设置页。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SettingsTarget {
+    Nfc,
+    App,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettingsRequest {
+    pub target: SettingsTarget,
+}